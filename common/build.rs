@@ -2,5 +2,9 @@ fn main() -> std::io::Result<()> {
     tonic_build::configure()
         .build_client(false)
         .build_server(false)
+        .type_attribute(
+            ".common.TunnelMessage.message",
+            "#[allow(clippy::large_enum_variant)]",
+        )
         .compile_protos(&["../proto/common.proto"], &["../proto/"])
 }
@@ -0,0 +1,101 @@
+use std::{
+    convert::Infallible,
+    future::poll_fn,
+    pin::Pin,
+    task::{Context, Poll},
+    time::Duration,
+};
+
+use http::{HeaderMap, HeaderName, HeaderValue};
+use hyper::body::{Bytes, Frame, SizeHint};
+use tokio::time::{error::Elapsed, timeout};
+
+/// A fully buffered body, optionally followed by trailers
+#[derive(Debug, Default)]
+pub struct Body {
+    data: Option<Bytes>,
+    trailers: Option<HeaderMap>,
+}
+
+impl Body {
+    pub fn new(data: Bytes, trailers: Vec<(HeaderName, HeaderValue)>) -> Self {
+        Self {
+            data: (!data.is_empty()).then_some(data),
+            trailers: (!trailers.is_empty()).then(|| trailers.into_iter().collect()),
+        }
+    }
+}
+
+impl From<Bytes> for Body {
+    fn from(data: Bytes) -> Self {
+        Self::new(data, vec![])
+    }
+}
+
+impl hyper::body::Body for Body {
+    type Data = Bytes;
+    type Error = Infallible;
+
+    fn poll_frame(
+        self: Pin<&mut Self>,
+        _: &mut Context<'_>,
+    ) -> Poll<Option<Result<Frame<Self::Data>, Self::Error>>> {
+        let this = self.get_mut();
+        if let Some(data) = this.data.take() {
+            return Poll::Ready(Some(Ok(Frame::data(data))));
+        }
+        Poll::Ready(
+            this.trailers
+                .take()
+                .map(|trailers| Ok(Frame::trailers(trailers))),
+        )
+    }
+
+    fn is_end_stream(&self) -> bool {
+        self.data.is_none() && self.trailers.is_none()
+    }
+
+    fn size_hint(&self) -> SizeHint {
+        // an exact size makes HTTP/1 use `Content-Length`, trailers need chunked encoding instead
+        let len = self.data.as_ref().map_or(0, |data| data.len() as u64);
+        match &self.trailers {
+            Some(_) => {
+                let mut hint = SizeHint::new();
+                hint.set_lower(len);
+                hint
+            }
+            None => SizeHint::with_exact(len),
+        }
+    }
+}
+
+/// Buffers `body` along with its trailers, giving up when no frame comes within `idle`
+pub async fn collect<B>(
+    mut body: B,
+    idle: Option<Duration>,
+) -> Result<Result<(Bytes, Vec<(HeaderName, HeaderValue)>), B::Error>, Elapsed>
+where
+    B: hyper::body::Body<Data = Bytes> + Unpin,
+{
+    let (mut data, mut trailers) = (Vec::new(), vec![]);
+    loop {
+        let frame = poll_fn(|cx| Pin::new(&mut body).poll_frame(cx));
+        let frame = match idle {
+            Some(idle) => timeout(idle, frame).await?,
+            None => frame.await,
+        };
+        let frame = match frame {
+            Some(Ok(frame)) => frame,
+            Some(Err(err)) => return Ok(Err(err)),
+            None => return Ok(Ok((Bytes::from(data), trailers))),
+        };
+        match frame.into_data() {
+            Ok(chunk) => data.extend_from_slice(&chunk),
+            Err(frame) => {
+                if let Ok(map) = frame.into_trailers() {
+                    trailers.extend(map.iter().map(|(k, v)| (k.clone(), v.clone())));
+                }
+            }
+        }
+    }
+}
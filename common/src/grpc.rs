@@ -1,6 +1,6 @@
-use std::str::FromStr;
+use std::{net::SocketAddr, str::FromStr, time::Duration};
 
-use http::{HeaderName, HeaderValue, Method, StatusCode, Uri};
+use http::{uri::Scheme, HeaderName, HeaderValue, Method, StatusCode, Uri, Version};
 use hyper::body::Bytes;
 use tonic::Status;
 
@@ -11,14 +11,23 @@ impl From<(u64, crate::IncomingRequest)> for IncomingRequest {
         let crate::IncomingRequest {
             method,
             uri,
+            version,
             headers,
             body,
+            trailers,
+            connection,
+            request_id,
+            timeouts,
         } = request;
 
         let method = method.as_str().to_owned();
         let uri = uri.to_string();
+        let version = format!("{version:?}");
         let headers = headers.into_iter().map(Header::from).collect();
         let body = body.to_vec();
+        let trailers = trailers.into_iter().map(Header::from).collect();
+        let connection = Some(Connection::from(connection));
+        let timeouts = Some(Timeouts::from(timeouts));
 
         Self {
             id,
@@ -26,6 +35,11 @@ impl From<(u64, crate::IncomingRequest)> for IncomingRequest {
             method,
             headers,
             body,
+            connection,
+            version,
+            trailers,
+            request_id,
+            timeouts,
         }
     }
 }
@@ -40,22 +54,124 @@ impl TryFrom<IncomingRequest> for crate::IncomingRequest {
             uri,
             headers,
             body,
+            connection,
+            version,
+            trailers,
+            request_id,
+            timeouts,
         } = value;
 
         let method = Method::from_bytes(method.as_bytes())
             .map_err(|_| Status::invalid_argument("Invalid method"))?;
         let uri = Uri::from_str(&uri).map_err(|_| Status::invalid_argument("Invalid uri"))?;
+        let version =
+            parse_version(&version).ok_or_else(|| Status::invalid_argument("Invalid version"))?;
         let headers = headers
             .into_iter()
             .map(<(HeaderName, HeaderValue)>::try_from)
             .collect::<Result<_, _>>()?;
         let body = Bytes::from(body);
+        let trailers = trailers
+            .into_iter()
+            .map(<(HeaderName, HeaderValue)>::try_from)
+            .collect::<Result<_, _>>()?;
+        let connection = connection
+            .ok_or_else(|| Status::invalid_argument("Missing connection"))?
+            .try_into()?;
+        let timeouts = timeouts
+            .ok_or_else(|| Status::invalid_argument("Missing timeouts"))?
+            .into();
 
         Ok(Self {
             method,
             uri,
+            version,
             headers,
             body,
+            trailers,
+            connection,
+            request_id,
+            timeouts,
+        })
+    }
+}
+
+impl From<crate::timeouts::Timeouts> for Timeouts {
+    fn from(timeouts: crate::timeouts::Timeouts) -> Self {
+        // sub millisecond timeouts would read as none
+        let millis = |timeout: Option<Duration>| {
+            timeout.map_or(0, |timeout| (timeout.as_millis() as u64).max(1))
+        };
+        Self {
+            connect: millis(timeouts.connect),
+            ttfb: millis(timeouts.ttfb),
+            idle: millis(timeouts.idle),
+            total: millis(timeouts.total),
+        }
+    }
+}
+
+impl From<Timeouts> for crate::timeouts::Timeouts {
+    fn from(timeouts: Timeouts) -> Self {
+        let duration = |millis: u64| (millis > 0).then(|| Duration::from_millis(millis));
+        Self {
+            connect: duration(timeouts.connect),
+            ttfb: duration(timeouts.ttfb),
+            idle: duration(timeouts.idle),
+            total: duration(timeouts.total),
+        }
+    }
+}
+
+impl From<crate::Connection> for Connection {
+    fn from(connection: crate::Connection) -> Self {
+        let crate::Connection {
+            remote_addr,
+            local_port,
+            scheme,
+            tls_version,
+            sni,
+            alpn,
+        } = connection;
+
+        Self {
+            remote_addr: remote_addr.to_string(),
+            local_port: local_port as u32,
+            scheme: scheme.to_string(),
+            tls_version: tls_version.unwrap_or_default(),
+            sni: sni.unwrap_or_default(),
+            alpn: alpn.unwrap_or_default(),
+        }
+    }
+}
+
+impl TryFrom<Connection> for crate::Connection {
+    type Error = Status;
+
+    fn try_from(value: Connection) -> Result<Self, Self::Error> {
+        let Connection {
+            remote_addr,
+            local_port,
+            scheme,
+            tls_version,
+            sni,
+            alpn,
+        } = value;
+
+        let remote_addr = SocketAddr::from_str(&remote_addr)
+            .map_err(|_| Status::invalid_argument("Invalid remote address"))?;
+        let local_port = u16::try_from(local_port)
+            .map_err(|_| Status::invalid_argument("Invalid local port"))?;
+        let scheme =
+            Scheme::from_str(&scheme).map_err(|_| Status::invalid_argument("Invalid scheme"))?;
+
+        Ok(Self {
+            remote_addr,
+            local_port,
+            scheme,
+            tls_version: (!tls_version.is_empty()).then_some(tls_version),
+            sni: (!sni.is_empty()).then_some(sni),
+            alpn: (!alpn.is_empty()).then_some(alpn),
         })
     }
 }
@@ -64,19 +180,25 @@ impl From<(u64, crate::OutgoingResponse)> for OutgoingResponse {
     fn from((id, response): (u64, crate::OutgoingResponse)) -> Self {
         let crate::OutgoingResponse {
             status,
+            version,
             headers,
             body,
+            trailers,
         } = response;
 
         let status = status.as_u16() as u32;
+        let version = format!("{version:?}");
         let headers = headers.into_iter().map(Header::from).collect();
         let body = body.to_vec();
+        let trailers = trailers.into_iter().map(Header::from).collect();
 
         Self {
             id,
             status,
             headers,
             body,
+            version,
+            trailers,
         }
     }
 }
@@ -90,26 +212,48 @@ impl TryFrom<OutgoingResponse> for crate::OutgoingResponse {
             status,
             headers,
             body,
+            version,
+            trailers,
         } = value;
 
         let status =
             u16::try_from(status).map_err(|_| Status::invalid_argument("Invalid status"))?;
         let status = StatusCode::from_u16(status)
             .map_err(|_| Status::invalid_argument("Invalid status code"))?;
+        let version =
+            parse_version(&version).ok_or_else(|| Status::invalid_argument("Invalid version"))?;
         let headers = headers
             .into_iter()
             .map(<(HeaderName, HeaderValue)>::try_from)
             .collect::<Result<_, _>>()?;
         let body = Bytes::from(body);
+        let trailers = trailers
+            .into_iter()
+            .map(<(HeaderName, HeaderValue)>::try_from)
+            .collect::<Result<_, _>>()?;
 
         Ok(Self {
             status,
+            version,
             headers,
             body,
+            trailers,
         })
     }
 }
 
+// parses the `Debug` representation of `Version`, an empty string is the default version
+fn parse_version(version: &str) -> Option<Version> {
+    match version {
+        "HTTP/0.9" => Some(Version::HTTP_09),
+        "HTTP/1.0" => Some(Version::HTTP_10),
+        "" | "HTTP/1.1" => Some(Version::HTTP_11),
+        "HTTP/2.0" => Some(Version::HTTP_2),
+        "HTTP/3.0" => Some(Version::HTTP_3),
+        _ => None,
+    }
+}
+
 impl From<(HeaderName, HeaderValue)> for Header {
     fn from((k, v): (HeaderName, HeaderValue)) -> Self {
         Self {
@@ -133,3 +277,19 @@ impl TryFrom<Header> for (HeaderName, HeaderValue) {
         }
     }
 }
+
+impl From<IncomingRequest> for TunnelMessage {
+    fn from(request: IncomingRequest) -> Self {
+        Self {
+            message: Some(tunnel_message::Message::Request(request)),
+        }
+    }
+}
+
+impl From<Cancel> for TunnelMessage {
+    fn from(cancel: Cancel) -> Self {
+        Self {
+            message: Some(tunnel_message::Message::Cancel(cancel)),
+        }
+    }
+}
@@ -0,0 +1,334 @@
+use std::{fmt, net::IpAddr, str::FromStr};
+
+use http::{
+    header::{CONNECTION, FORWARDED, TE, TRANSFER_ENCODING, UPGRADE},
+    HeaderName, HeaderValue,
+};
+
+use crate::Connection;
+
+pub const KEEP_ALIVE: HeaderName = HeaderName::from_static("keep-alive");
+pub const PROXY_CONNECTION: HeaderName = HeaderName::from_static("proxy-connection");
+pub const X_FORWARDED_FOR: HeaderName = HeaderName::from_static("x-forwarded-for");
+pub const X_FORWARDED_HOST: HeaderName = HeaderName::from_static("x-forwarded-host");
+pub const X_FORWARDED_PROTO: HeaderName = HeaderName::from_static("x-forwarded-proto");
+pub const X_REQUEST_ID: HeaderName = HeaderName::from_static("x-request-id");
+
+/// Longest `X-Request-Id` accepted from callers
+const MAX_REQUEST_ID_LEN: usize = 128;
+
+/// Connection-specific headers that must not be forwarded (RFC 9110 section 7.6.1)
+const HOP_BY_HOP: [HeaderName; 6] = [
+    CONNECTION,
+    KEEP_ALIVE,
+    PROXY_CONNECTION,
+    TE,
+    TRANSFER_ENCODING,
+    UPGRADE,
+];
+
+/// The caller's `X-Request-Id` if it's sane, a new UUIDv7 otherwise
+pub fn request_id(value: Option<&HeaderValue>) -> String {
+    value
+        .and_then(|value| value.to_str().ok())
+        .filter(|id| {
+            !id.is_empty()
+                && id.len() <= MAX_REQUEST_ID_LEN
+                && id.bytes().all(|b| b.is_ascii_graphic())
+        })
+        .map_or_else(|| uuid::Uuid::now_v7().to_string(), str::to_owned)
+}
+
+/// Removes hop-by-hop headers, including the ones listed in `Connection`
+///
+/// `TE: trailers` is kept, since it's needed end to end to receive trailers
+pub fn strip_hop_by_hop(headers: &mut Vec<(HeaderName, HeaderValue)>) {
+    let listed = headers
+        .iter()
+        .filter(|(k, _)| k == CONNECTION)
+        .filter_map(|(_, v)| v.to_str().ok())
+        .flat_map(|v| v.split(','))
+        .filter_map(|name| HeaderName::from_bytes(name.trim().as_bytes()).ok())
+        .collect::<Vec<_>>();
+
+    headers.retain(|(k, v)| {
+        (k == TE && v == "trailers") || (!HOP_BY_HOP.contains(k) && !listed.contains(k))
+    });
+}
+
+/// Appends the caller to `X-Forwarded-For` and `Forwarded`, and sets `X-Forwarded-Proto` and `X-Forwarded-Host`
+///
+/// Forwarding headers sent by the caller are kept only if it's `trusted`, its proto and host then win over ours
+pub fn forwarded(
+    headers: &mut Vec<(HeaderName, HeaderValue)>,
+    connection: &Connection,
+    host: Option<&HeaderValue>,
+    trusted: bool,
+) {
+    if !trusted {
+        headers.retain(|(k, _)| {
+            k != X_FORWARDED_FOR
+                && k != FORWARDED
+                && k != X_FORWARDED_PROTO
+                && k != X_FORWARDED_HOST
+        });
+    }
+
+    let client = connection.remote_addr.ip();
+    let proto = connection.scheme.as_str();
+    let mut element = match client {
+        IpAddr::V4(ip) => format!("for={ip};proto={proto}"),
+        IpAddr::V6(ip) => format!("for=\"[{ip}]\";proto={proto}"),
+    };
+    // a host with a port isn't a token, so it's always quoted
+    if let Some(host) = host.and_then(|host| host.to_str().ok()) {
+        element.push_str(";host=\"");
+        element.push_str(&host.replace('\\', "\\\\").replace('"', "\\\""));
+        element.push('"');
+    }
+
+    append_list(headers, X_FORWARDED_FOR, &client.to_string());
+    append_list(headers, FORWARDED, &element);
+    if !headers.iter().any(|(k, _)| k == X_FORWARDED_PROTO) {
+        if let Ok(proto) = HeaderValue::from_str(proto) {
+            headers.push((X_FORWARDED_PROTO, proto));
+        }
+    }
+    if !headers.iter().any(|(k, _)| k == X_FORWARDED_HOST) {
+        if let Some(host) = host {
+            headers.push((X_FORWARDED_HOST, host.clone()));
+        }
+    }
+}
+
+/// Merges every occurrence of a list header into a single one, with `value` appended
+fn append_list(headers: &mut Vec<(HeaderName, HeaderValue)>, name: HeaderName, value: &str) {
+    // bytes rather than strings, so that opaque values are kept too
+    let mut list = vec![];
+    for (_, v) in headers.iter().filter(|(k, v)| k == name && !v.is_empty()) {
+        list.extend_from_slice(v.as_bytes());
+        list.extend_from_slice(b", ");
+    }
+    list.extend_from_slice(value.as_bytes());
+    // values come from valid headers, so the join is valid too
+    let Ok(value) = HeaderValue::from_bytes(&list) else {
+        return;
+    };
+    headers.retain(|(k, _)| k != name);
+    headers.push((name, value));
+}
+
+/// Header handling applied in one direction of the tunnel
+#[derive(Debug, Clone, Default)]
+pub struct HeaderPolicy {
+    /// when not empty, only these headers are forwarded
+    pub allow: Vec<HeaderName>,
+    /// headers never forwarded
+    pub deny: Vec<HeaderName>,
+    /// headers removed after forwarding headers have been added
+    pub remove: Vec<HeaderName>,
+    /// headers replacing any existing value
+    pub set: Vec<HeaderRule>,
+    /// headers appended to existing values
+    pub add: Vec<HeaderRule>,
+}
+
+impl HeaderPolicy {
+    /// Strips hop-by-hop headers and applies allow and deny lists
+    pub fn filter(&self, headers: &mut Vec<(HeaderName, HeaderValue)>) {
+        strip_hop_by_hop(headers);
+        headers.retain(|(k, _)| {
+            (self.allow.is_empty() || self.allow.contains(k)) && !self.deny.contains(k)
+        });
+    }
+
+    /// Applies remove, set and add rules, in this order
+    pub fn rewrite(&self, headers: &mut Vec<(HeaderName, HeaderValue)>) {
+        headers.retain(|(k, _)| {
+            !self.remove.contains(k) && !self.set.iter().any(|rule| rule.name == k)
+        });
+        headers.extend(
+            self.set
+                .iter()
+                .chain(&self.add)
+                .map(|rule| (rule.name.clone(), rule.value.clone())),
+        );
+    }
+}
+
+/// A `Name: value` pair
+#[derive(Debug, Clone)]
+pub struct HeaderRule {
+    pub name: HeaderName,
+    pub value: HeaderValue,
+}
+
+impl FromStr for HeaderRule {
+    type Err = InvalidHeaderRule;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (name, value) = s.split_once(':').ok_or(InvalidHeaderRule)?;
+        Ok(Self {
+            name: HeaderName::from_str(name.trim()).map_err(|_| InvalidHeaderRule)?,
+            value: HeaderValue::from_str(value.trim()).map_err(|_| InvalidHeaderRule)?,
+        })
+    }
+}
+
+#[derive(Debug)]
+pub struct InvalidHeaderRule;
+
+impl fmt::Display for InvalidHeaderRule {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("expected `Name: value`")
+    }
+}
+
+impl std::error::Error for InvalidHeaderRule {}
+
+#[cfg(test)]
+mod tests {
+    use http::uri::Scheme;
+
+    use super::*;
+
+    fn connection(remote_addr: &str, scheme: Scheme) -> Connection {
+        Connection {
+            remote_addr: remote_addr.parse().unwrap(),
+            local_port: 8080,
+            scheme,
+            tls_version: None,
+            sni: None,
+            alpn: None,
+        }
+    }
+
+    fn headers(pairs: &[(&HeaderName, &str)]) -> Vec<(HeaderName, HeaderValue)> {
+        pairs
+            .iter()
+            .map(|(k, v)| ((*k).clone(), HeaderValue::from_str(v).unwrap()))
+            .collect()
+    }
+
+    fn get<'a>(headers: &'a [(HeaderName, HeaderValue)], name: &HeaderName) -> Vec<&'a str> {
+        headers
+            .iter()
+            .filter(|(k, _)| k == name)
+            .map(|(_, v)| v.to_str().unwrap())
+            .collect()
+    }
+
+    fn caller_headers() -> Vec<(HeaderName, HeaderValue)> {
+        headers(&[
+            (&X_FORWARDED_FOR, "203.0.113.7"),
+            (&X_FORWARDED_FOR, "198.51.100.2"),
+            (&FORWARDED, "for=203.0.113.7;proto=https"),
+            (&X_FORWARDED_PROTO, "https"),
+            (&X_FORWARDED_HOST, "public.example"),
+        ])
+    }
+
+    #[test]
+    fn forwarded_untrusted() {
+        let mut headers = caller_headers();
+        let host = HeaderValue::from_static("example.com:8443");
+        forwarded(
+            &mut headers,
+            &connection("192.0.2.1:40000", Scheme::HTTP),
+            Some(&host),
+            false,
+        );
+        assert_eq!(get(&headers, &X_FORWARDED_FOR), ["192.0.2.1"]);
+        assert_eq!(
+            get(&headers, &FORWARDED),
+            ["for=192.0.2.1;proto=http;host=\"example.com:8443\""]
+        );
+        assert_eq!(get(&headers, &X_FORWARDED_PROTO), ["http"]);
+        assert_eq!(get(&headers, &X_FORWARDED_HOST), ["example.com:8443"]);
+    }
+
+    #[test]
+    fn forwarded_trusted() {
+        let mut headers = caller_headers();
+        let host = HeaderValue::from_static("internal:8080");
+        forwarded(
+            &mut headers,
+            &connection("[2001:db8::1]:40000", Scheme::HTTP),
+            Some(&host),
+            true,
+        );
+        assert_eq!(
+            get(&headers, &X_FORWARDED_FOR),
+            ["203.0.113.7, 198.51.100.2, 2001:db8::1"]
+        );
+        assert_eq!(
+            get(&headers, &FORWARDED),
+            ["for=203.0.113.7;proto=https, for=\"[2001:db8::1]\";proto=http;host=\"internal:8080\""]
+        );
+        // the proxy knows the original scheme and host
+        assert_eq!(get(&headers, &X_FORWARDED_PROTO), ["https"]);
+        assert_eq!(get(&headers, &X_FORWARDED_HOST), ["public.example"]);
+    }
+
+    #[test]
+    fn forwarded_trusted_without_headers() {
+        let mut headers = vec![];
+        forwarded(
+            &mut headers,
+            &connection("192.0.2.1:40000", Scheme::HTTPS),
+            None,
+            true,
+        );
+        assert_eq!(get(&headers, &X_FORWARDED_FOR), ["192.0.2.1"]);
+        assert_eq!(get(&headers, &FORWARDED), ["for=192.0.2.1;proto=https"]);
+        assert_eq!(get(&headers, &X_FORWARDED_PROTO), ["https"]);
+        assert!(get(&headers, &X_FORWARDED_HOST).is_empty());
+    }
+
+    #[test]
+    fn forwarded_host_escaping() {
+        let mut headers = vec![];
+        let host = HeaderValue::from_static(r#"a"b\c"#);
+        forwarded(
+            &mut headers,
+            &connection("192.0.2.1:40000", Scheme::HTTP),
+            Some(&host),
+            false,
+        );
+        assert_eq!(
+            get(&headers, &FORWARDED),
+            [r#"for=192.0.2.1;proto=http;host="a\"b\\c""#]
+        );
+    }
+
+    #[test]
+    fn append_list_merges() {
+        let mut headers = headers(&[
+            (&X_FORWARDED_FOR, "203.0.113.7"),
+            (&X_REQUEST_ID, "id"),
+            (&X_FORWARDED_FOR, ""),
+            (&X_FORWARDED_FOR, "198.51.100.2, 198.51.100.3"),
+        ]);
+        append_list(&mut headers, X_FORWARDED_FOR, "192.0.2.1");
+        assert_eq!(get(&headers, &X_REQUEST_ID), ["id"]);
+        assert_eq!(
+            get(&headers, &X_FORWARDED_FOR),
+            ["203.0.113.7, 198.51.100.2, 198.51.100.3, 192.0.2.1"]
+        );
+
+        let mut headers = vec![];
+        append_list(&mut headers, X_FORWARDED_FOR, "192.0.2.1");
+        assert_eq!(get(&headers, &X_FORWARDED_FOR), ["192.0.2.1"]);
+    }
+
+    #[test]
+    fn append_list_opaque() {
+        let mut headers = vec![(
+            X_FORWARDED_FOR,
+            HeaderValue::from_bytes(b"caf\xe9").unwrap(),
+        )];
+        append_list(&mut headers, X_FORWARDED_FOR, "192.0.2.1");
+        assert_eq!(headers.len(), 1);
+        assert_eq!(headers[0].1.as_bytes(), b"caf\xe9, 192.0.2.1");
+    }
+}
@@ -0,0 +1,74 @@
+use std::{convert::Infallible, io, net::SocketAddr, sync::Arc};
+
+use http::{header::CONTENT_TYPE, HeaderValue, Method, Request, Response, StatusCode};
+use hyper::{
+    body::{Bytes, Incoming},
+    service::service_fn,
+};
+use hyper_util::{
+    rt::{TokioExecutor, TokioIo},
+    server::conn::auto,
+};
+use tokio::net::TcpListener;
+use tracing::{debug, error};
+
+use crate::body::Body;
+
+/// Tells whether a daemon can take traffic
+pub trait Readiness: Send + Sync + 'static {
+    /// The error is the reason, for humans
+    fn ready(&self) -> Result<(), String>;
+}
+
+/// Serves liveness on `/healthz` and readiness on `/readyz`
+pub async fn serve(addr: SocketAddr, readiness: Arc<dyn Readiness>) -> io::Result<()> {
+    let listener = TcpListener::bind(&addr).await?;
+    debug!("Health listening on http://{}", addr);
+
+    loop {
+        let (stream, _) = listener.accept().await?;
+        let readiness = readiness.clone();
+        tokio::spawn(async move {
+            if let Err(err) = auto::Builder::new(TokioExecutor::new())
+                .serve_connection(
+                    TokioIo::new(stream),
+                    service_fn(move |req| health(req, readiness.clone())),
+                )
+                .await
+            {
+                error!("Failed to serve health: {err}");
+            }
+        });
+    }
+}
+
+async fn health(
+    req: Request<Incoming>,
+    readiness: Arc<dyn Readiness>,
+) -> Result<Response<Body>, Infallible> {
+    Ok(respond(&req, readiness.as_ref()).unwrap_or_else(|| {
+        let mut response = Response::new(Body::default());
+        *response.status_mut() = StatusCode::NOT_FOUND;
+        response
+    }))
+}
+
+/// Answers `/healthz` and `/readyz`, `None` for anything else
+pub fn respond<B>(req: &Request<B>, readiness: &dyn Readiness) -> Option<Response<Body>> {
+    let (status, body) = match (req.method(), req.uri().path()) {
+        (&Method::GET, "/healthz") => (StatusCode::OK, "ok".to_owned()),
+        (&Method::GET, "/readyz") => match readiness.ready() {
+            Ok(()) => (StatusCode::OK, "ready".to_owned()),
+            Err(reason) => (StatusCode::SERVICE_UNAVAILABLE, reason),
+        },
+        _ => return None,
+    };
+
+    let mut response = Response::new(Body::from(Bytes::from(body)));
+    *response.status_mut() = status;
+    response.headers_mut().insert(
+        CONTENT_TYPE,
+        HeaderValue::from_static("text/plain; charset=utf-8"),
+    );
+    Some(response)
+}
@@ -1,19 +1,67 @@
-use http::{HeaderName, HeaderValue, Method, StatusCode, Uri};
+use std::net::SocketAddr;
+
+use http::{uri::Scheme, HeaderName, HeaderValue, Method, StatusCode, Uri, Version};
 use hyper::body::Bytes;
 
+/// Tunnel used when none is specified
+pub const DEFAULT_TUNNEL: &str = "default";
+
+/// `StreamRequests` response metadata carrying the session id, for porcoc to report about its session
+pub const SESSION_METADATA: &str = "porco-session";
+
+pub mod body;
 pub mod grpc;
+pub mod headers;
+pub mod health;
+pub mod metrics;
+pub mod problem;
+pub mod telemetry;
+pub mod timeouts;
 
 #[derive(Debug, Clone)]
 pub struct IncomingRequest {
     pub method: Method,
     pub uri: Uri,
+    pub version: Version,
     pub headers: Vec<(HeaderName, HeaderValue)>,
     pub body: Bytes,
+    pub trailers: Vec<(HeaderName, HeaderValue)>,
+    pub connection: Connection,
+    /// See [`headers::request_id`]
+    pub request_id: String,
+    /// `total` is the time left to answer, once the request leaves porcod
+    pub timeouts: timeouts::Timeouts,
+}
+
+/// Details about the caller connection to porcod
+#[derive(Debug, Clone)]
+pub struct Connection {
+    pub remote_addr: SocketAddr,
+    pub local_port: u16,
+    pub scheme: Scheme,
+    pub tls_version: Option<String>,
+    pub sni: Option<String>,
+    pub alpn: Option<Vec<u8>>,
 }
 
 #[derive(Debug, Clone)]
 pub struct OutgoingResponse {
     pub status: StatusCode,
+    pub version: Version,
     pub headers: Vec<(HeaderName, HeaderValue)>,
     pub body: Bytes,
+    pub trailers: Vec<(HeaderName, HeaderValue)>,
+}
+
+impl From<StatusCode> for OutgoingResponse {
+    /// An empty response, for errors generated by porcod or porcoc themselves
+    fn from(status: StatusCode) -> Self {
+        Self {
+            status,
+            version: Version::default(),
+            headers: vec![],
+            body: Bytes::new(),
+            trailers: vec![],
+        }
+    }
 }
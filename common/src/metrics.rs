@@ -0,0 +1,74 @@
+use std::{convert::Infallible, io, net::SocketAddr};
+
+use http::{header::CONTENT_TYPE, Method, Request, Response, StatusCode};
+use hyper::{
+    body::{Bytes, Incoming},
+    service::service_fn,
+};
+use hyper_util::{
+    rt::{TokioExecutor, TokioIo},
+    server::conn::auto,
+};
+use prometheus::{Encoder, TextEncoder};
+use tokio::net::TcpListener;
+use tracing::{debug, error};
+
+use crate::body::Body;
+
+/// The method as a label value, extension methods share one to keep series bounded
+pub fn method_label(method: &Method) -> &'static str {
+    match *method {
+        Method::GET => "GET",
+        Method::HEAD => "HEAD",
+        Method::POST => "POST",
+        Method::PUT => "PUT",
+        Method::DELETE => "DELETE",
+        Method::CONNECT => "CONNECT",
+        Method::OPTIONS => "OPTIONS",
+        Method::TRACE => "TRACE",
+        Method::PATCH => "PATCH",
+        _ => "other",
+    }
+}
+
+/// Serves metrics in Prometheus text format on `/metrics`
+pub async fn serve(addr: SocketAddr) -> io::Result<()> {
+    let listener = TcpListener::bind(&addr).await?;
+    debug!("Metrics listening on http://{}", addr);
+
+    loop {
+        let (stream, _) = listener.accept().await?;
+        tokio::spawn(async move {
+            if let Err(err) = auto::Builder::new(TokioExecutor::new())
+                .serve_connection(TokioIo::new(stream), service_fn(metrics))
+                .await
+            {
+                error!("Failed to serve metrics: {err}");
+            }
+        });
+    }
+}
+
+async fn metrics(req: Request<Incoming>) -> Result<Response<Body>, Infallible> {
+    if req.method() != Method::GET || req.uri().path() != "/metrics" {
+        let mut response = Response::new(Body::default());
+        *response.status_mut() = StatusCode::NOT_FOUND;
+        return Ok(response);
+    }
+
+    let encoder = TextEncoder::new();
+    let mut buffer = vec![];
+    let mut response = match encoder.encode(&prometheus::gather(), &mut buffer) {
+        Ok(()) => Response::new(Body::from(Bytes::from(buffer))),
+        Err(err) => {
+            error!("Failed to encode metrics: {err}");
+            let mut response = Response::new(Body::default());
+            *response.status_mut() = StatusCode::INTERNAL_SERVER_ERROR;
+            return Ok(response);
+        }
+    };
+    if let Ok(content_type) = encoder.format_type().parse() {
+        response.headers_mut().insert(CONTENT_TYPE, content_type);
+    }
+    Ok(response)
+}
@@ -0,0 +1,145 @@
+use std::fmt;
+
+use http::{header::CONTENT_TYPE, HeaderValue, StatusCode, Version};
+use serde_json::json;
+
+use crate::OutgoingResponse;
+
+pub const CONTENT_TYPE_PROBLEM: &str = "application/problem+json";
+
+/// Why porcod or porcoc answered in place of the backend
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Kind {
+    /// the caller isn't allowed, or no tunnel matches the request
+    Rejected,
+    /// no porcoc serves the tunnel
+    NoTunnel,
+    /// the tunnel or its target can't take requests right now
+    Unavailable,
+    /// the tunnel is at capacity
+    Overloaded,
+    /// the caller exceeded its rate limit
+    RateLimited,
+    /// porcoc couldn't connect to the backend
+    BackendConnect,
+    /// the backend connection failed after being established
+    BadGateway,
+    /// porcoc or the backend didn't answer in time
+    Timeout,
+    Internal,
+}
+
+impl Kind {
+    pub fn status(self) -> StatusCode {
+        match self {
+            Self::Rejected => StatusCode::FORBIDDEN,
+            Self::RateLimited => StatusCode::TOO_MANY_REQUESTS,
+            Self::NoTunnel | Self::Unavailable | Self::Overloaded => {
+                StatusCode::SERVICE_UNAVAILABLE
+            }
+            Self::BackendConnect | Self::BadGateway => StatusCode::BAD_GATEWAY,
+            Self::Timeout => StatusCode::GATEWAY_TIMEOUT,
+            Self::Internal => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Self::Rejected => "rejected",
+            Self::NoTunnel => "no-tunnel",
+            Self::Unavailable => "unavailable",
+            Self::Overloaded => "overloaded",
+            Self::RateLimited => "rate-limited",
+            Self::BackendConnect => "backend-connect",
+            Self::BadGateway => "bad-gateway",
+            Self::Timeout => "timeout",
+            Self::Internal => "internal",
+        }
+    }
+
+    pub fn title(self) -> &'static str {
+        match self {
+            Self::Rejected => "Request rejected",
+            Self::NoTunnel => "No tunnel available",
+            Self::Unavailable => "Service unavailable",
+            Self::Overloaded => "Service overloaded",
+            Self::RateLimited => "Too many requests",
+            Self::BackendConnect => "Backend unreachable",
+            Self::BadGateway => "Backend failed",
+            Self::Timeout => "Request timed out",
+            Self::Internal => "Internal error",
+        }
+    }
+}
+
+/// An error answered as RFC 9457 problem details
+#[derive(Debug, Clone)]
+pub struct Problem {
+    pub kind: Kind,
+    pub status: StatusCode,
+    /// internal details, callers may not get them
+    pub detail: Option<String>,
+}
+
+impl Problem {
+    pub fn new(kind: Kind) -> Self {
+        Self {
+            kind,
+            status: kind.status(),
+            detail: None,
+        }
+    }
+
+    /// Overrides the status of the kind
+    pub fn with_status(mut self, status: StatusCode) -> Self {
+        self.status = status;
+        self
+    }
+
+    pub fn with_detail(mut self, detail: impl Into<String>) -> Self {
+        self.detail = Some(detail.into());
+        self
+    }
+
+    /// The response body, `detail` only included with `details`
+    pub fn to_json(&self, request_id: &str, details: bool) -> serde_json::Value {
+        let mut body = json!({
+            "type": format!("urn:porco:problem:{}", self.kind.as_str()),
+            "title": self.kind.title(),
+            "status": self.status.as_u16(),
+            "request_id": request_id,
+        });
+        if let Some(detail) = self.detail.as_ref().filter(|_| details) {
+            body["detail"] = json!(detail);
+        }
+        body
+    }
+
+    pub fn into_response(self, request_id: &str, details: bool) -> OutgoingResponse {
+        OutgoingResponse {
+            status: self.status,
+            version: Version::default(),
+            headers: vec![(CONTENT_TYPE, HeaderValue::from_static(CONTENT_TYPE_PROBLEM))],
+            body: self.to_json(request_id, details).to_string().into(),
+            trailers: vec![],
+        }
+    }
+}
+
+impl From<Kind> for Problem {
+    fn from(kind: Kind) -> Self {
+        Self::new(kind)
+    }
+}
+
+impl fmt::Display for Problem {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.kind.title())?;
+        if let Some(detail) = &self.detail {
+            write!(f, ": {detail}")?;
+        }
+        Ok(())
+    }
+}
+
+impl std::error::Error for Problem {}
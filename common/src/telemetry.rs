@@ -0,0 +1,159 @@
+use http::{HeaderName, HeaderValue};
+use opentelemetry::{
+    propagation::{Extractor, Injector},
+    trace::TracerProvider as _,
+    KeyValue,
+};
+use opentelemetry_otlp::WithExportConfig;
+use opentelemetry_sdk::{
+    propagation::TraceContextPropagator, runtime, trace::TracerProvider, Resource,
+};
+use tonic::metadata::{KeyRef, MetadataKey, MetadataMap, MetadataValue};
+use tracing::{error, Span};
+use tracing_opentelemetry::OpenTelemetrySpanExt;
+use tracing_subscriber::{
+    filter::LevelFilter, layer::SubscriberExt, util::SubscriberInitExt, EnvFilter, Layer,
+};
+
+/// Keeps spans flowing to the OTLP collector, flushing them on drop
+#[derive(Debug)]
+pub struct Telemetry(Option<TracerProvider>);
+
+impl Drop for Telemetry {
+    fn drop(&mut self) {
+        if let Some(provider) = self.0.take() {
+            if let Err(err) = provider.shutdown() {
+                error!("Failed to flush spans: {err}");
+            }
+        }
+    }
+}
+
+/// Sets up logging from `RUST_LOG`, and exports spans to `otlp_endpoint` (gRPC) if given
+///
+/// W3C trace context is used for propagation either way
+pub fn init(
+    service_name: &'static str,
+    otlp_endpoint: Option<String>,
+) -> anyhow::Result<Telemetry> {
+    opentelemetry::global::set_text_map_propagator(TraceContextPropagator::new());
+
+    let provider = otlp_endpoint
+        .map(|endpoint| {
+            let exporter = opentelemetry_otlp::SpanExporter::builder()
+                .with_tonic()
+                .with_endpoint(endpoint)
+                .build()?;
+            anyhow::Ok(
+                TracerProvider::builder()
+                    .with_batch_exporter(exporter, runtime::Tokio)
+                    .with_resource(Resource::new([KeyValue::new("service.name", service_name)]))
+                    .build(),
+            )
+        })
+        .transpose()?;
+
+    // spans are exported regardless of the log level
+    let otel = provider.as_ref().map(|provider| {
+        tracing_opentelemetry::layer()
+            .with_tracer(provider.tracer(service_name))
+            .with_filter(LevelFilter::INFO)
+    });
+    tracing_subscriber::registry()
+        .with(tracing_subscriber::fmt::layer().with_filter(EnvFilter::from_default_env()))
+        .with(otel)
+        .try_init()?;
+
+    Ok(Telemetry(provider))
+}
+
+/// Makes `span` a child of the trace context found in `headers`, if any
+pub fn set_parent(span: &Span, headers: &[(HeaderName, HeaderValue)]) {
+    let context = opentelemetry::global::get_text_map_propagator(|propagator| {
+        propagator.extract(&HeaderPairs(headers))
+    });
+    span.set_parent(context);
+}
+
+/// Writes the trace context of `span` into `headers`, replacing the one of the caller
+pub fn inject(span: &Span, headers: &mut Vec<(HeaderName, HeaderValue)>) {
+    let context = span.context();
+    opentelemetry::global::get_text_map_propagator(|propagator| {
+        propagator.inject_context(&context, &mut HeaderPairsMut(headers))
+    });
+}
+
+/// Makes `span` a child of the trace context found in gRPC `metadata`, if any
+pub fn set_parent_from_metadata(span: &Span, metadata: &MetadataMap) {
+    let context = opentelemetry::global::get_text_map_propagator(|propagator| {
+        propagator.extract(&Metadata(metadata))
+    });
+    span.set_parent(context);
+}
+
+/// Writes the trace context of `span` into gRPC `metadata`
+pub fn inject_metadata(span: &Span, metadata: &mut MetadataMap) {
+    let context = span.context();
+    opentelemetry::global::get_text_map_propagator(|propagator| {
+        propagator.inject_context(&context, &mut MetadataMut(metadata))
+    });
+}
+
+struct HeaderPairs<'a>(&'a [(HeaderName, HeaderValue)]);
+
+impl Extractor for HeaderPairs<'_> {
+    fn get(&self, key: &str) -> Option<&str> {
+        self.0
+            .iter()
+            .find(|(k, _)| k.as_str() == key)
+            .and_then(|(_, v)| v.to_str().ok())
+    }
+
+    fn keys(&self) -> Vec<&str> {
+        self.0.iter().map(|(k, _)| k.as_str()).collect()
+    }
+}
+
+struct HeaderPairsMut<'a>(&'a mut Vec<(HeaderName, HeaderValue)>);
+
+impl Injector for HeaderPairsMut<'_> {
+    fn set(&mut self, key: &str, value: String) {
+        let (Ok(name), Ok(value)) = (HeaderName::try_from(key), HeaderValue::try_from(value))
+        else {
+            return;
+        };
+        self.0.retain(|(k, _)| k != name);
+        self.0.push((name, value));
+    }
+}
+
+struct Metadata<'a>(&'a MetadataMap);
+
+impl Extractor for Metadata<'_> {
+    fn get(&self, key: &str) -> Option<&str> {
+        self.0.get(key).and_then(|v| v.to_str().ok())
+    }
+
+    fn keys(&self) -> Vec<&str> {
+        self.0
+            .keys()
+            .filter_map(|key| match key {
+                KeyRef::Ascii(key) => Some(key.as_str()),
+                KeyRef::Binary(_) => None,
+            })
+            .collect()
+    }
+}
+
+struct MetadataMut<'a>(&'a mut MetadataMap);
+
+impl Injector for MetadataMut<'_> {
+    fn set(&mut self, key: &str, value: String) {
+        if let (Ok(key), Ok(value)) = (
+            MetadataKey::from_bytes(key.as_bytes()),
+            MetadataValue::try_from(value),
+        ) {
+            self.0.insert(key, value);
+        }
+    }
+}
@@ -0,0 +1,96 @@
+use std::{str::FromStr, time::Duration};
+
+use tokio::time::Instant;
+
+/// Stands for "never", like tokio does for timeouts too large to represent
+const FAR_FUTURE: Duration = Duration::from_secs(86400 * 365 * 30);
+
+/// Time limits of a request, unset ones don't apply
+///
+/// Timeouts are written as comma or whitespace separated `KEY=DURATION` options, with durations in `ms`, `s`, `m`
+/// or `h`:
+/// - `connect` connecting to the backend
+/// - `ttfb` from sending the request to the backend until its response head
+/// - `idle` between body chunks, of the caller request and of the backend response
+/// - `total` the whole exchange
+///
+/// e.g. `connect=2s,ttfb=10s`, `idle=30s total=5m`
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Timeouts {
+    pub connect: Option<Duration>,
+    pub ttfb: Option<Duration>,
+    pub idle: Option<Duration>,
+    pub total: Option<Duration>,
+}
+
+impl Timeouts {
+    /// Fills the unset timeouts from `fallback`
+    pub fn or(self, fallback: Self) -> Self {
+        Self {
+            connect: self.connect.or(fallback.connect),
+            ttfb: self.ttfb.or(fallback.ttfb),
+            idle: self.idle.or(fallback.idle),
+            total: self.total.or(fallback.total),
+        }
+    }
+}
+
+impl FromStr for Timeouts {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut timeouts = Self::default();
+        for token in s
+            .split(|c: char| c == ',' || c.is_whitespace())
+            .filter(|token| !token.is_empty())
+        {
+            let (key, value) = token
+                .split_once('=')
+                .ok_or_else(|| Error::Key(token.into()))?;
+            let duration =
+                Some(parse_duration(value).ok_or_else(|| Error::Duration(value.into()))?);
+            match key {
+                "connect" => timeouts.connect = duration,
+                "ttfb" => timeouts.ttfb = duration,
+                "idle" => timeouts.idle = duration,
+                "total" => timeouts.total = duration,
+                _ => return Err(Error::Key(key.into())),
+            }
+        }
+        if timeouts == Self::default() {
+            return Err(Error::Empty);
+        }
+        Ok(timeouts)
+    }
+}
+
+/// `duration` after `start`, far in the future if that can't be represented
+pub fn deadline(start: Instant, duration: Duration) -> Instant {
+    start
+        .checked_add(duration)
+        .unwrap_or_else(|| start + FAR_FUTURE)
+}
+
+// parses a non zero `N` followed by `ms`, `s`, `m` or `h`
+fn parse_duration(value: &str) -> Option<Duration> {
+    let split = value.find(|c: char| !c.is_ascii_digit())?;
+    let (amount, unit) = value.split_at(split);
+    let amount = u64::from_str(amount).ok().filter(|amount| *amount > 0)?;
+    match unit {
+        "ms" => Some(Duration::from_millis(amount)),
+        "s" => Some(Duration::from_secs(amount)),
+        "m" => amount.checked_mul(60).map(Duration::from_secs),
+        "h" => amount.checked_mul(3600).map(Duration::from_secs),
+        _ => None,
+    }
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+    #[error("timeouts without options")]
+    Empty,
+    #[error("unknown timeout `{0}`, expected `connect`, `ttfb`, `idle` or `total`")]
+    Key(String),
+    #[error("invalid duration `{0}`, expected `N` followed by `ms`, `s`, `m` or `h`")]
+    Duration(String),
+}
@@ -0,0 +1,89 @@
+use std::{sync::Mutex, time::Duration};
+
+use common::timeouts;
+use tokio::time::Instant;
+use tracing::{debug, error};
+
+/// Stops calling the target after consecutive failures, letting a trial call through once `open_for` has elapsed
+#[derive(Debug)]
+pub struct Breaker {
+    failures: u32,
+    open_for: Duration,
+    state: Mutex<State>,
+}
+
+#[derive(Debug, Clone, Copy)]
+enum State {
+    Closed {
+        failures: u32,
+    },
+    Open {
+        until: Instant,
+    },
+    /// a trial call is in flight, another one is let through after `until` if it gets lost
+    HalfOpen {
+        until: Instant,
+    },
+}
+
+impl Breaker {
+    pub fn new(failures: u32, open_for: Duration) -> Self {
+        Self {
+            failures,
+            open_for,
+            state: Mutex::new(State::Closed { failures: 0 }),
+        }
+    }
+
+    /// Whether a call can go to the target, taking the trial slot when due
+    pub(crate) fn allow(&self) -> bool {
+        let mut state = self.state.lock().unwrap();
+        let now = Instant::now();
+        match *state {
+            State::Closed { .. } => true,
+            State::Open { until } | State::HalfOpen { until } if until <= now => {
+                debug!("Circuit breaker half open, trying the target");
+                *state = State::HalfOpen {
+                    until: timeouts::deadline(now, self.open_for),
+                };
+                true
+            }
+            State::Open { .. } | State::HalfOpen { .. } => false,
+        }
+    }
+
+    pub(crate) fn success(&self) {
+        let mut state = self.state.lock().unwrap();
+        if !matches!(*state, State::Closed { .. }) {
+            debug!("Circuit breaker closed");
+        }
+        *state = State::Closed { failures: 0 };
+    }
+
+    pub(crate) fn failure(&self) {
+        let mut state = self.state.lock().unwrap();
+        *state = match *state {
+            State::Closed { failures } if failures + 1 < self.failures => State::Closed {
+                failures: failures + 1,
+            },
+            _ => {
+                error!("Circuit breaker open for {:?}", self.open_for);
+                State::Open {
+                    until: timeouts::deadline(Instant::now(), self.open_for),
+                }
+            }
+        };
+    }
+
+    /// When the open breaker becomes due for a trial call, `None` if it's not open or already due
+    pub(crate) fn retry_at(&self) -> Option<Instant> {
+        match *self.state.lock().unwrap() {
+            State::Open { until } if Instant::now() < until => Some(until),
+            _ => None,
+        }
+    }
+
+    pub(crate) fn is_open(&self) -> bool {
+        self.retry_at().is_some()
+    }
+}
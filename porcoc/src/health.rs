@@ -0,0 +1,125 @@
+use std::{
+    sync::atomic::{AtomicBool, Ordering},
+    time::Duration,
+};
+
+use common::health::Readiness;
+use tokio::{
+    sync::watch,
+    time::{interval, Instant},
+};
+use tracing::{debug, error};
+
+use crate::{breaker::Breaker, metrics};
+
+/// porcoc readiness and target health, fed by the tunnel, the target probe and the circuit breaker
+#[derive(Debug)]
+pub struct Health {
+    connected: AtomicBool,
+    probe: AtomicBool,
+    breaker: Option<Breaker>,
+    /// target health as last computed, reported to porcod on change
+    target: watch::Sender<bool>,
+}
+
+impl Health {
+    /// Without a probe the target is taken for healthy until calls fail
+    pub fn new(probe: bool, breaker: Option<Breaker>) -> Self {
+        metrics::TARGET_HEALTHY.set(i64::from(!probe));
+        Self {
+            connected: AtomicBool::new(false),
+            probe: AtomicBool::new(!probe),
+            breaker,
+            target: watch::Sender::new(!probe),
+        }
+    }
+
+    pub(crate) fn set_connected(&self, connected: bool) {
+        self.connected.store(connected, Ordering::Relaxed);
+    }
+
+    /// Whether a call can go to the target, fails fast otherwise
+    pub(crate) fn allow(&self) -> bool {
+        let allowed =
+            self.probe.load(Ordering::Relaxed) && self.breaker.as_ref().is_none_or(Breaker::allow);
+        self.update();
+        allowed
+    }
+
+    /// Feeds the circuit breaker with the outcome of a call
+    pub(crate) fn record(&self, success: bool) {
+        if let Some(breaker) = &self.breaker {
+            if success {
+                breaker.success();
+            } else {
+                breaker.failure();
+            }
+            self.update();
+        }
+    }
+
+    /// When the target may be healthy again, without anything else happening
+    pub(crate) fn retry_at(&self) -> Option<Instant> {
+        self.breaker.as_ref().and_then(Breaker::retry_at)
+    }
+
+    pub(crate) fn subscribe(&self) -> watch::Receiver<bool> {
+        self.target.subscribe()
+    }
+
+    /// Recomputes the target health
+    pub(crate) fn update(&self) {
+        let healthy = self.probe.load(Ordering::Relaxed)
+            && !self.breaker.as_ref().is_some_and(Breaker::is_open);
+        self.target.send_if_modified(|target| {
+            let changed = *target != healthy;
+            *target = healthy;
+            changed
+        });
+        metrics::TARGET_HEALTHY.set(i64::from(healthy));
+    }
+}
+
+impl Readiness for Health {
+    fn ready(&self) -> Result<(), String> {
+        if !self.connected.load(Ordering::Relaxed) {
+            return Err("tunnel not established".to_owned());
+        }
+        if !*self.target.borrow() {
+            return Err("target unhealthy".to_owned());
+        }
+        Ok(())
+    }
+}
+
+/// Checks `url` every `period`, the target is healthy while it answers with a 2xx within the period
+pub async fn probe(
+    client: reqwest::Client,
+    url: reqwest::Url,
+    period: Duration,
+    health: &Health,
+) -> anyhow::Result<()> {
+    let mut interval = interval(period);
+    loop {
+        interval.tick().await;
+        let healthy = match client.get(url.clone()).timeout(period).send().await {
+            Ok(response) if response.status().is_success() => true,
+            Ok(response) => {
+                debug!("Target probe answered {}", response.status());
+                false
+            }
+            Err(err) => {
+                debug!("Target probe failed: {err}");
+                false
+            }
+        };
+        if health.probe.swap(healthy, Ordering::Relaxed) != healthy {
+            if healthy {
+                debug!("Target is healthy again");
+            } else {
+                error!("Target is unhealthy");
+            }
+            health.update();
+        }
+    }
+}
@@ -0,0 +1,185 @@
+use std::{fmt, str::FromStr};
+
+use common::headers::X_FORWARDED_PROTO;
+use reqwest::header::{HeaderName, HeaderValue, FORWARDED, LOCATION, SET_COOKIE};
+use tonic::transport::Uri;
+
+/// The `Host` the target sees
+#[derive(Debug, Clone, Default)]
+pub enum HostPolicy {
+    /// the public host the caller asked for
+    Preserve,
+    /// the host of the target url
+    #[default]
+    Target,
+    /// always the same value
+    Fixed(HeaderValue),
+}
+
+impl HostPolicy {
+    /// The `Host` to send, `None` leaves the one of the target url
+    pub(crate) fn host(&self, public: Option<&HeaderValue>) -> Option<HeaderValue> {
+        match self {
+            Self::Preserve => public.cloned(),
+            Self::Target => None,
+            Self::Fixed(host) => Some(host.clone()),
+        }
+    }
+}
+
+impl FromStr for HostPolicy {
+    type Err = InvalidHostPolicy;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "preserve" => Ok(Self::Preserve),
+            "target" => Ok(Self::Target),
+            _ => {
+                let host = s
+                    .strip_prefix("fixed=")
+                    .filter(|host| !host.is_empty())
+                    .ok_or(InvalidHostPolicy)?;
+                Ok(Self::Fixed(
+                    HeaderValue::from_str(host).map_err(|_| InvalidHostPolicy)?,
+                ))
+            }
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct InvalidHostPolicy;
+
+impl fmt::Display for InvalidHostPolicy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("expected `preserve`, `target` or `fixed=HOST`")
+    }
+}
+
+impl std::error::Error for InvalidHostPolicy {}
+
+/// Points response headers naming the target back at the public host
+pub(crate) struct Rewrite<'a> {
+    /// hosts the target may know itself as, with their port
+    pub backend: Vec<(&'a str, Option<u16>)>,
+    pub public_scheme: &'a str,
+    pub public_host: &'a str,
+}
+
+impl Rewrite<'_> {
+    pub(crate) fn apply(&self, headers: &mut [(HeaderName, HeaderValue)]) {
+        for (k, v) in headers {
+            let rewritten = if *k == LOCATION {
+                self.location(v)
+            } else if *k == SET_COOKIE {
+                self.cookie(v)
+            } else {
+                None
+            };
+            if let Some(value) = rewritten.and_then(|value| HeaderValue::from_str(&value).ok()) {
+                *v = value;
+            }
+        }
+    }
+
+    /// Absolute redirects to the target go to the public host, with the public scheme
+    ///
+    /// Redirects already naming the public host are left alone, the target knows their scheme best
+    fn location(&self, value: &HeaderValue) -> Option<String> {
+        let uri = Uri::from_str(value.to_str().ok()?).ok()?;
+        let host = uri.host()?;
+        let port = uri.port_u16().or_else(|| default_port(uri.scheme_str()?));
+        let (public, public_port) = split_port(self.public_host);
+        if public.eq_ignore_ascii_case(host) && (public_port.is_none() || public_port == port) {
+            return None;
+        }
+        self.backend
+            .iter()
+            .any(|(name, p)| {
+                name.eq_ignore_ascii_case(host) && (p.is_none() || port.is_none() || *p == port)
+            })
+            .then(|| {
+                let path = uri.path_and_query().map_or("/", |path| path.as_str());
+                format!("{}://{}{path}", self.public_scheme, self.public_host)
+            })
+    }
+
+    /// Cookies scoped to the target host get scoped to the public one
+    fn cookie(&self, value: &HeaderValue) -> Option<String> {
+        let value = value.to_str().ok()?;
+        let public = host_name(self.public_host);
+        let mut rewritten = false;
+        let attributes = value
+            .split(';')
+            .enumerate()
+            .map(|(i, attribute)| {
+                // the first pair is the cookie itself
+                let Some((name, domain)) = attribute.split_once('=').filter(|_| i > 0) else {
+                    return attribute.to_owned();
+                };
+                let domain = domain.trim();
+                let matches = name.trim().eq_ignore_ascii_case("domain")
+                    && self
+                        .backend
+                        .iter()
+                        .any(|(name, _)| name.eq_ignore_ascii_case(domain.trim_start_matches('.')));
+                if matches && !domain.eq_ignore_ascii_case(public) {
+                    rewritten = true;
+                    format!(" Domain={public}")
+                } else {
+                    attribute.to_owned()
+                }
+            })
+            .collect::<Vec<_>>();
+        rewritten.then(|| attributes.join(";"))
+    }
+}
+
+/// The scheme the caller used, as told by the first proxy
+///
+/// porcod appends itself to `Forwarded`, earlier elements come from trusted proxies and win over `X-Forwarded-Proto`
+pub(crate) fn forwarded_proto(headers: &[(HeaderName, HeaderValue)]) -> Option<&'static str> {
+    let list = |name: &HeaderName| {
+        headers
+            .iter()
+            .filter(|(k, _)| k == name)
+            .filter_map(|(_, v)| v.to_str().ok())
+            .flat_map(|v| v.split(','))
+            .map(str::trim)
+            .collect::<Vec<_>>()
+    };
+    let forwarded = list(&FORWARDED);
+    let proto = match forwarded.as_slice() {
+        [first, _, ..] => first
+            .split(';')
+            .filter_map(|pair| pair.trim().split_once('='))
+            .find(|(name, _)| name.eq_ignore_ascii_case("proto"))
+            .map(|(_, proto)| proto.trim_matches('"')),
+        _ => None,
+    }
+    .or_else(|| list(&X_FORWARDED_PROTO).first().copied())?;
+    // anything else has no business in a Location
+    ["http", "https"]
+        .into_iter()
+        .find(|scheme| scheme.eq_ignore_ascii_case(proto))
+}
+
+/// Splits the port off a `host[:port]`, IPv6 addresses keep their brackets
+pub(crate) fn split_port(authority: &str) -> (&str, Option<u16>) {
+    match authority.rsplit_once(':') {
+        Some((host, port)) if !port.contains(']') => (host, port.parse().ok()),
+        _ => (authority, None),
+    }
+}
+
+fn default_port(scheme: &str) -> Option<u16> {
+    match scheme {
+        "http" => Some(80),
+        "https" => Some(443),
+        _ => None,
+    }
+}
+
+fn host_name(authority: &str) -> &str {
+    split_port(authority).0
+}
@@ -1,90 +1,552 @@
-use std::{borrow::Cow, str::FromStr};
+use std::{
+    collections::HashMap,
+    str::FromStr,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
 
-use prost::bytes::Bytes;
-use reqwest::StatusCode;
+use anyhow::Context;
+use common::{
+    grpc::tunnel_message::Message,
+    headers::{self, X_FORWARDED_HOST, X_REQUEST_ID},
+    problem::{Kind, Problem},
+    telemetry,
+    timeouts::{self, Timeouts},
+};
+use reqwest::{
+    header::{HeaderValue, HOST},
+    StatusCode, Version,
+};
+use tokio::{
+    task::{AbortHandle, JoinSet},
+    time::{sleep, sleep_until, timeout, Instant},
+};
 use tokio_stream::StreamExt;
 use tonic::{
-    transport::{Certificate, ClientTlsConfig, Uri},
-    Status,
+    transport::{Certificate, Channel, ClientTlsConfig, Endpoint, Uri},
+    Status, Streaming,
 };
-use tracing::{debug, error};
+use tracing::{debug, error, field, info_span, Instrument, Span};
 
+pub mod breaker;
 mod grpc;
+pub mod health;
+pub mod host;
+mod metrics;
+pub mod retry;
+pub mod tls;
+
+const RECONNECT_DELAY: Duration = Duration::from_secs(1);
+const MAX_RECONNECT_DELAY: Duration = Duration::from_secs(30);
+
+/// The private service, and how it's called
+#[derive(Debug)]
+pub struct Target {
+    pub url: Uri,
+    pub clients: Clients,
+    pub host_policy: host::HostPolicy,
+    /// used where porcod doesn't set them
+    pub timeouts: Timeouts,
+    pub retry: retry::Policy,
+    pub health: Arc<health::Health>,
+    /// keep internal details out of error responses
+    pub hide_error_details: bool,
+}
+
+/// Target clients by connect timeout, which reqwest only sets per client
+#[derive(Debug, Default)]
+pub struct Clients {
+    tls: tls::Tls,
+    clients: Mutex<HashMap<Option<Duration>, reqwest::Client>>,
+}
+
+impl Clients {
+    pub fn new(tls: tls::Tls) -> Self {
+        Self {
+            tls,
+            clients: Mutex::default(),
+        }
+    }
+
+    pub fn get(&self, connect: Option<Duration>) -> reqwest::Result<reqwest::Client> {
+        let mut clients = self.clients.lock().unwrap();
+        if let Some(client) = clients.get(&connect) {
+            return Ok(client.clone());
+        }
+        let mut builder = self.tls.apply(reqwest::Client::builder());
+        if let Some(connect) = connect {
+            builder = builder.connect_timeout(connect);
+        }
+        let client = builder.build()?;
+        clients.insert(connect, client.clone());
+        Ok(client)
+    }
+}
 
 pub async fn start(
     certs: Option<Certificate>,
     porco_url: Uri,
-    target_url: Uri,
+    tunnel: String,
+    identity: String,
+    target: Target,
 ) -> anyhow::Result<()> {
     let mut endpoint = tonic::transport::Endpoint::new(porco_url)?;
     if let Some(certs) = certs {
         endpoint = endpoint.tls_config(ClientTlsConfig::new().ca_certificate(certs))?;
     }
+    let target = Arc::new(target);
+    let health = &target.health;
+
+    let mut delay = RECONNECT_DELAY;
+    let mut reconnecting = false;
+    loop {
+        let subscription = common::grpc::Subscription {
+            tunnel: tunnel.clone(),
+            identity: identity.clone(),
+            version: env!("CARGO_PKG_VERSION").to_owned(),
+        };
+        match connect(&endpoint, subscription).await {
+            Ok((porco_client, stream, session)) => {
+                metrics::CONNECTED.set(1);
+                health.set_connected(true);
+                delay = RECONNECT_DELAY;
+                reconnecting = true;
+                run(porco_client, stream, session, &target).await;
+                metrics::CONNECTED.set(0);
+                health.set_connected(false);
+                error!("Tunnel to porcod closed");
+            }
+            // the first connection fails fast, to surface configuration errors
+            Err(err) if !reconnecting => return Err(err),
+            Err(err) => error!("Failed to reconnect to porcod: {err}"),
+        }
+
+        sleep(delay).await;
+        delay = (delay * 2).min(MAX_RECONNECT_DELAY);
+        debug!("Reconnecting to porcod");
+        metrics::RECONNECTS.inc();
+    }
+}
+
+async fn connect(
+    endpoint: &Endpoint,
+    subscription: common::grpc::Subscription,
+) -> anyhow::Result<(
+    grpc::inner_client::InnerClient<Channel>,
+    Streaming<common::grpc::TunnelMessage>,
+    u64,
+)> {
     let client = endpoint.connect().await?;
     let mut porco_client = grpc::inner_client::InnerClient::new(client);
-    let response = porco_client.stream_requests(common::grpc::Void {}).await?;
-    let mut stream = response.into_inner();
+    let response = porco_client.stream_requests(subscription).await?;
+    let session = response
+        .metadata()
+        .get(common::SESSION_METADATA)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.parse().ok())
+        .context("porcod didn't tell the session")?;
+    Ok((porco_client, response.into_inner(), session))
+}
 
-    let target_client = reqwest::Client::new();
+/// Serves the requests of a tunnel session, until porcod closes it
+async fn run(
+    mut porco_client: grpc::inner_client::InnerClient<Channel>,
+    mut stream: Streaming<common::grpc::TunnelMessage>,
+    session: u64,
+    target: &Arc<Target>,
+) {
+    // requests are served concurrently, so that a cancel can abort the in-flight call
+    let mut tasks = JoinSet::new();
+    let mut in_flight = HashMap::<u64, AbortHandle>::new();
+    let health = &target.health;
+    let mut target_health = health.subscribe();
+    // porcod takes new sessions for healthy, until told otherwise
+    target_health.mark_changed();
+    loop {
+        let retry_at = health.retry_at();
+        tokio::select! {
+            Ok(()) = target_health.changed() => {
+                let healthy = *target_health.borrow_and_update();
+                report_health(&mut porco_client, session, healthy).await;
+            }
+            // the open circuit breaker becomes due for a trial call
+            _ = sleep_until(retry_at.unwrap_or_else(Instant::now)), if retry_at.is_some() => health.update(),
+            message = stream.next() => {
+                let Some(message) = message else {
+                    break;
+                };
+                let request = match message.map(|message| message.message) {
+                    Ok(Some(Message::Request(request))) => Ok(request),
+                    Ok(Some(Message::Cancel(cancel))) => {
+                        if let Some(handle) = in_flight.remove(&cancel.id) {
+                            debug!("Request {} cancelled by porcod", cancel.id);
+                            handle.abort();
+                        }
+                        metrics::IN_FLIGHT.set(in_flight.len() as i64);
+                        continue;
+                    }
+                    Ok(None) => continue,
+                    Err(err) => Err(err),
+                };
+                let id = request.as_ref().map(|request| request.id).unwrap_or_default();
+                let handle = tasks.spawn({
+                    let porco_client = porco_client.clone();
+                    let target = target.clone();
+                    async move {
+                        serve(request, porco_client, &target).await;
+                        id
+                    }
+                });
+                if id != 0 {
+                    in_flight.insert(id, handle);
+                }
+                metrics::IN_FLIGHT.set(in_flight.len() as i64);
+            }
+            Some(res) = tasks.join_next() => {
+                // aborted tasks have already been removed
+                if let Ok(id) = res {
+                    in_flight.remove(&id);
+                }
+                metrics::IN_FLIGHT.set(in_flight.len() as i64);
+            }
+        }
+    }
+
+    // dropping `tasks` aborts whatever is still in flight, porcod answers them anyway
+    metrics::IN_FLIGHT.set(0);
+}
+
+/// Tells porcod whether this session should get requests
+async fn report_health(
+    porco_client: &mut grpc::inner_client::InnerClient<Channel>,
+    session: u64,
+    healthy: bool,
+) {
+    debug!("Reporting target healthy: {healthy}");
+    let report = common::grpc::TargetHealth { session, healthy };
+    if let Err(err) = porco_client.report_health(report).await {
+        error!("Failed to report target health: {err}");
+    }
+}
 
-    while let Some(request) = stream.next().await {
-        let res = dispatch(
-            request,
-            &target_url,
-            &target_client,
+async fn serve(
+    request: Result<common::grpc::IncomingRequest, Status>,
+    porco_client: grpc::inner_client::InnerClient<Channel>,
+    target: &Target,
+) {
+    let request_id = request
+        .as_ref()
+        .map(|request| request.request_id.clone())
+        .unwrap_or_default();
+    let span = info_span!(
+        "dispatch",
+        id = request
+            .as_ref()
+            .map(|request| request.id)
+            .unwrap_or_default(),
+        request_id,
+        status = field::Empty,
+    );
+    let res = if target.health.allow() {
+        dispatch(request, target).instrument(span.clone()).await
+    } else {
+        debug!(parent: &span, "Target unavailable, failing fast");
+        metrics::DISPATCH_ERRORS
+            .with_label_values(&["unavailable"])
+            .inc();
+        Err((
+            request.ok().map(|request| request.id),
+            Problem::new(Kind::Unavailable).with_detail("Target unhealthy"),
+        ))
+    };
+    let res = res.unwrap_or_else(|(id, problem)| {
+        debug!(parent: &span, "Request failed: {problem}");
+        (
+            id.unwrap_or_default(),
+            problem.into_response(&request_id, !target.hide_error_details),
         )
-        .await
-        .unwrap_or_else(|(id, error)| {
-            (id.unwrap_or_default(), common::OutgoingResponse {
-                status: StatusCode::INTERNAL_SERVER_ERROR,
-                headers: vec![],
-                body: Bytes::from_iter(error.bytes()),
-            })
-        });
-        let request = common::grpc::OutgoingResponse::from(res);
-        if let Err(err)= porco_client.send_response(request).await {
-            error!("{err}");
-        }
+    });
+    span.record("status", res.1.status.as_u16());
+    send_response(porco_client, res, &span).await;
+}
+
+async fn send_response(
+    mut porco_client: grpc::inner_client::InnerClient<Channel>,
+    res: (u64, common::OutgoingResponse),
+    span: &Span,
+) {
+    let mut response = tonic::Request::new(common::grpc::OutgoingResponse::from(res));
+    telemetry::inject_metadata(span, response.metadata_mut());
+    if let Err(err) = porco_client.send_response(response).await {
+        error!(parent: span, "{err}");
     }
-    Ok(())
 }
 
 async fn dispatch(
     request: Result<common::grpc::IncomingRequest, Status>,
-    target_url: &Uri,
-    target_client: &reqwest::Client,
-) -> Result<(u64, common::OutgoingResponse), (Option<u64>, Cow<'static, str>)> {
+    target: &Target,
+) -> Result<(u64, common::OutgoingResponse), (Option<u64>, Problem)> {
     debug!("Dispatching {request:?}");
-    let request = request.map_err(|err| (None, Cow::Owned(format!("Received error: {err}"))))?;
+    let request = request.map_err(|err| {
+        metrics::DISPATCH_ERRORS
+            .with_label_values(&["received"])
+            .inc();
+        (
+            None,
+            Problem::new(Kind::Internal).with_detail(format!("Received error: {err}")),
+        )
+    })?;
     let id = request.id;
     let common::IncomingRequest {
         uri,
         method,
-        headers,
+        version,
+        mut headers,
         body,
-    } = common::IncomingRequest::try_from(request).map_err(|err| (Some(id), Cow::Owned(format!("Conversion error: {err}"))))?;
+        trailers,
+        connection,
+        request_id,
+        timeouts,
+    } = common::IncomingRequest::try_from(request).map_err(|err| {
+        metrics::DISPATCH_ERRORS
+            .with_label_values(&["conversion"])
+            .inc();
+        (
+            Some(id),
+            Problem::new(Kind::Internal).with_detail(format!("Conversion error: {err}")),
+        )
+    })?;
+    debug!(
+        remote_addr = %connection.remote_addr,
+        local_port = connection.local_port,
+        scheme = %connection.scheme,
+        tls_version = connection.tls_version.as_deref(),
+        sni = connection.sni.as_deref(),
+        "Calling {method} {uri} for request {id}"
+    );
 
     // do we really have to re-parse the Url?
-    let mut url = reqwest::Url::from_str(&target_url.to_string()).map_err(|_| (Some(id), Cow::Borrowed("Invalid uri")))?;
+    let mut url = reqwest::Url::from_str(&target.url.to_string()).map_err(|_| {
+        metrics::DISPATCH_ERRORS.with_label_values(&["uri"]).inc();
+        (
+            Some(id),
+            Problem::new(Kind::Internal).with_detail("Invalid uri"),
+        )
+    })?;
     url.set_path(uri.path());
     url.set_query(uri.query());
     //url.set_fragment(uri.fragment());
 
-    let mut builder = target_client.request(method, url);
-    for (k,v) in headers {
-        builder = builder.header(k,v);
+    // the backend sees the porcoc span as its parent
+    telemetry::set_parent(&Span::current(), &headers);
+    telemetry::inject(&Span::current(), &mut headers);
+
+    headers::strip_hop_by_hop(&mut headers);
+    // the backend logs the same id as porcod and porcoc
+    if let Ok(value) = HeaderValue::from_str(&request_id) {
+        headers.retain(|(k, _)| k != X_REQUEST_ID);
+        headers.push((X_REQUEST_ID, value));
+    }
+    // porcod sends the caller Host as `X-Forwarded-Host`
+    let public_host = headers
+        .iter()
+        .find(|(k, _)| k == X_FORWARDED_HOST)
+        .map(|(_, v)| v.clone())
+        .or_else(|| {
+            uri.authority()
+                .and_then(|authority| HeaderValue::from_str(authority.as_str()).ok())
+        });
+    // porcod sets `X-Forwarded-Proto`, keeping the one of trusted proxies in front of it
+    let public_scheme = host::forwarded_proto(&headers).unwrap_or(connection.scheme.as_str());
+    let host = target.host_policy.host(public_host.as_ref());
+    headers.retain(|(k, _)| k != HOST);
+    if let Some(host) = &host {
+        headers.push((HOST, host.clone()));
     }
-    let response = builder.body(body).send().await.map_err(|err| (Some(id), Cow::Owned(format!("Call error: {err}"))))?;
+    // porcod timeouts are the most specific, but its deadline can only be shortened
+    let timeouts = Timeouts {
+        total: [timeouts.total, target.timeouts.total]
+            .into_iter()
+            .flatten()
+            .min(),
+        ..timeouts.or(target.timeouts)
+    };
+    let start = Instant::now();
+    let deadline = timeouts.total.map(|total| timeouts::deadline(start, total));
+    let client = target.clients.get(timeouts.connect).map_err(|err| {
+        metrics::DISPATCH_ERRORS
+            .with_label_values(&["client"])
+            .inc();
+        (
+            Some(id),
+            Problem::new(Kind::Internal).with_detail(format!("Client error: {err}")),
+        )
+    })?;
+
+    let retry = &target.retry;
+    retry.budget.deposit();
+    let retryable = retry.applies(
+        &method,
+        headers.iter().any(|(k, _)| k == retry::IDEMPOTENCY_KEY),
+    );
+    let mut attempt = 1;
+    let response = loop {
+        let mut builder = client.request(method.clone(), url.clone());
+        // HTTP/2 and later can't be forced on the target, they're negotiated by the client
+        if matches!(version, Version::HTTP_10 | Version::HTTP_11) {
+            builder = builder.version(version);
+        }
+        for (k, v) in &headers {
+            builder = builder.header(k.clone(), v.clone());
+        }
+        if let Some(deadline) = deadline {
+            builder = builder.timeout(deadline.saturating_duration_since(Instant::now()));
+        }
+        let body = if trailers.is_empty() {
+            reqwest::Body::from(body.clone())
+        } else {
+            reqwest::Body::wrap(common::body::Body::new(body.clone(), trailers.clone()))
+        };
+        let send = builder.body(body).send();
+        let res = match timeouts.ttfb {
+            Some(ttfb) => match timeout(ttfb, send).await {
+                Ok(res) => res.map_err(CallError::from),
+                Err(_) => Err(CallError::Ttfb(ttfb)),
+            },
+            None => send.await.map_err(CallError::from),
+        };
+
+        let reason = match &res {
+            Ok(response) => retry
+                .statuses
+                .contains(&response.status())
+                .then_some("status"),
+            Err(err) => {
+                let kind = err.kind();
+                retry.errors.contains(&kind).then_some(kind.as_str())
+            }
+        };
+        match reason {
+            Some(reason) if retryable && attempt < retry.max_attempts => {
+                let backoff = retry.backoff(attempt);
+                if deadline
+                    .is_some_and(|deadline| timeouts::deadline(Instant::now(), backoff) >= deadline)
+                {
+                    debug!("Attempt {attempt} failed ({reason}), no time left to retry");
+                    break res;
+                }
+                if !retry.budget.withdraw() {
+                    debug!("Attempt {attempt} failed ({reason}), retry budget exhausted");
+                    break res;
+                }
+                debug!("Attempt {attempt} failed ({reason}), retrying in {backoff:?}");
+                metrics::RETRIES.with_label_values(&[reason]).inc();
+                sleep(backoff).await;
+                attempt += 1;
+            }
+            _ => break res,
+        }
+    };
+    let response = response.map_err(|err| {
+        metrics::DISPATCH_ERRORS.with_label_values(&["call"]).inc();
+        target.health.record(false);
+        (Some(id), call_problem(&err, "Call"))
+    })?;
 
     let status = response.status();
-    let headers = response
+    let version = response.version();
+    let mut headers = response
         .headers()
         .iter()
         .map(|(k, v)| (k.clone(), v.clone()))
         .collect();
-    let body = response.bytes().await.map_err(|err| (Some(id), Cow::Owned(format!("Body error: {err}"))))?;
+    headers::strip_hop_by_hop(&mut headers);
+    if let Some(public_host) = public_host.as_ref().and_then(|host| host.to_str().ok()) {
+        let target_port = target
+            .url
+            .port_u16()
+            .unwrap_or(match target.url.scheme_str() {
+                Some("https") => 443,
+                _ => 80,
+            });
+        let mut backend = vec![(target.url.host().unwrap_or_default(), Some(target_port))];
+        // with an SNI name, the target may still know itself by its address
+        backend.extend(
+            target
+                .clients
+                .tls
+                .sni_host()
+                .map(|host| (host, Some(target_port))),
+        );
+        backend.extend(
+            host.as_ref()
+                .and_then(|host| host.to_str().ok())
+                .map(host::split_port),
+        );
+        host::Rewrite {
+            backend,
+            public_scheme,
+            public_host,
+        }
+        .apply(&mut headers);
+    }
+    let (body, trailers) = common::body::collect(reqwest::Body::from(response), timeouts.idle)
+        .await
+        .map_err(|_| CallError::Idle(timeouts.idle.unwrap_or_default()))
+        .and_then(|res| res.map_err(CallError::from))
+        .map_err(|err| {
+            metrics::DISPATCH_ERRORS.with_label_values(&["body"]).inc();
+            target.health.record(false);
+            (Some(id), call_problem(&err, "Body"))
+        })?;
+    // the target answering it's unavailable counts against the circuit breaker
+    target.health.record(!matches!(
+        status,
+        StatusCode::BAD_GATEWAY | StatusCode::SERVICE_UNAVAILABLE | StatusCode::GATEWAY_TIMEOUT
+    ));
+    metrics::BACKEND_DURATION.observe(start.elapsed().as_secs_f64());
+    metrics::BACKEND_RESPONSES
+        .with_label_values(&[common::metrics::method_label(&method), status.as_str()])
+        .inc();
+
+    Ok((
+        id,
+        common::OutgoingResponse {
+            status,
+            version,
+            headers,
+            body,
+            trailers,
+        },
+    ))
+}
+
+/// Why a call to the target failed
+#[derive(Debug, thiserror::Error)]
+enum CallError {
+    #[error(transparent)]
+    Reqwest(#[from] reqwest::Error),
+    #[error("no response head within {0:?}")]
+    Ttfb(Duration),
+    #[error("no body data within {0:?}")]
+    Idle(Duration),
+}
+
+impl CallError {
+    fn kind(&self) -> retry::ErrorKind {
+        match self {
+            Self::Reqwest(err) => retry::ErrorKind::of(err),
+            Self::Ttfb(_) | Self::Idle(_) => retry::ErrorKind::Timeout,
+        }
+    }
+}
 
-    Ok((id, common::OutgoingResponse {status, headers,
-        body
-    }))
+/// Tells apart the backend being unreachable, slow or failing
+fn call_problem(err: &CallError, what: &str) -> Problem {
+    let kind = match err.kind() {
+        retry::ErrorKind::Connect => Kind::BackendConnect,
+        retry::ErrorKind::Timeout => Kind::Timeout,
+        retry::ErrorKind::Transport => Kind::BadGateway,
+    };
+    Problem::new(kind).with_detail(format!("{what} error: {err}"))
 }
@@ -1,11 +1,25 @@
 use std::{
     fs,
     io::{self, Read},
+    net::SocketAddr,
     path::PathBuf,
+    sync::Arc,
+    time::Duration,
 };
 
 use clap::Parser;
+use common::timeouts::Timeouts;
+use porcoc::{
+    breaker::Breaker,
+    health::Health,
+    host::HostPolicy,
+    retry::{self, ErrorKind},
+    tls::Tls,
+    Clients,
+};
+use reqwest::StatusCode;
 use tonic::transport::{Certificate, Uri};
+use tracing::warn;
 
 /// PORCO client
 #[derive(Parser, Debug)]
@@ -15,6 +29,30 @@ struct Args {
     #[arg(short = 'u', long)]
     target_url: Uri,
 
+    /// Host sent to the target: "preserve" the public one, the "target" url one, or "fixed=HOST"; `Location` and `Set-Cookie` domains naming the target are rewritten to the public host
+    #[arg(long, default_value = "target")]
+    host_policy: HostPolicy,
+
+    /// CA bundle trusted for the target besides the system roots (pem format)
+    #[arg(long)]
+    target_ca: Option<PathBuf>,
+
+    /// client certificate chain presented to the target (pem format)
+    #[arg(long, requires = "target_key")]
+    target_cert: Option<PathBuf>,
+
+    /// client certificate private key (pem format)
+    #[arg(long, requires = "target_cert")]
+    target_key: Option<PathBuf>,
+
+    /// server name sent to the target, also its Host; the target is still connected at its url address
+    #[arg(long)]
+    target_sni: Option<String>,
+
+    /// don't verify the target certificate, for lab environments only
+    #[arg(long)]
+    target_insecure_skip_verify: bool,
+
     /// porco server url
     #[arg(short = 'U', long)]
     porcod_url: Uri,
@@ -22,20 +60,155 @@ struct Args {
     /// grpc public certificate (pem format)
     #[arg(short = 'C', long)]
     porcod_certs: Option<PathBuf>,
+
+    /// metrics bind address, serving Prometheus metrics on /metrics
+    #[arg(long)]
+    metrics_addr: Option<SocketAddr>,
+
+    /// admin bind address, serving /healthz and /readyz
+    #[arg(long)]
+    admin_addr: Option<SocketAddr>,
+
+    /// target path probed for readiness (e.g. /health), expecting a 2xx
+    #[arg(long)]
+    target_health_path: Option<String>,
+
+    /// seconds between target probes, also their timeout
+    #[arg(long, default_value_t = 10, value_parser = clap::value_parser!(u64).range(1..))]
+    target_health_interval: u64,
+
+    /// consecutive target failures opening the circuit breaker, which then fails fast with 503; no breaker if missing
+    #[arg(long, value_parser = clap::value_parser!(u32).range(1..))]
+    breaker_failures: Option<u32>,
+
+    /// seconds the circuit breaker stays open before letting a trial call through
+    #[arg(long, default_value_t = 30)]
+    breaker_open_secs: u64,
+
+    /// target timeouts (e.g. "connect=2s,ttfb=10s,idle=30s"), porcod ones take precedence but can't extend total
+    #[arg(long)]
+    timeouts: Option<Timeouts>,
+
+    /// attempts per target call, retrying only idempotent methods or requests with an Idempotency-Key
+    #[arg(long, default_value_t = 1, value_parser = clap::value_parser!(u32).range(1..))]
+    retry_attempts: u32,
+
+    /// milliseconds before the first retry, doubling at each further one
+    #[arg(long, default_value_t = 100)]
+    retry_backoff_ms: u64,
+
+    /// maximum milliseconds between retries
+    #[arg(long, default_value_t = 2000)]
+    retry_max_backoff_ms: u64,
+
+    /// target statuses to retry
+    #[arg(long, value_delimiter = ',', default_value = "502,503,504")]
+    retry_statuses: Vec<StatusCode>,
+
+    /// call errors to retry
+    #[arg(long, value_enum, value_delimiter = ',', default_value = "connect")]
+    retry_errors: Vec<ErrorKind>,
+
+    /// retries allowed per request on average, bounding load on a failing target
+    #[arg(long, default_value_t = 0.2)]
+    retry_budget: f64,
+
+    /// leave internal details out of error responses, only telling the kind of error
+    #[arg(long)]
+    hide_error_details: bool,
+
+    /// OTLP gRPC endpoint receiving spans (e.g. http://localhost:4317), no export if missing
+    #[arg(long)]
+    otlp_endpoint: Option<String>,
+
+    /// tunnel to serve
+    #[arg(short = 't', long, default_value = common::DEFAULT_TUNNEL)]
+    tunnel: String,
+
+    /// how this porcoc shows up in the porcod admin API, its address if missing
+    #[arg(short = 'i', long)]
+    identity: Option<String>,
 }
 
 #[tokio::main]
 async fn main() -> anyhow::Result<()> {
-    tracing_subscriber::fmt::init();
-
     let args = Args::parse();
+    let _telemetry = common::telemetry::init("porcoc", args.otlp_endpoint)?;
+    let breaker = args
+        .breaker_failures
+        .map(|failures| Breaker::new(failures, Duration::from_secs(args.breaker_open_secs)));
+    let health = Arc::new(Health::new(args.target_health_path.is_some(), breaker));
+
+    if args.target_insecure_skip_verify {
+        warn!("Target certificate verification is disabled, connections to the target can be intercepted");
+    }
+    let mut tls = Tls::default().insecure_skip_verify(args.target_insecure_skip_verify);
+    if let Some(ca) = &args.target_ca {
+        tls = tls.ca(ca)?;
+    }
+    if let Some((cert, key)) = args.target_cert.as_ref().zip(args.target_key.as_ref()) {
+        tls = tls.identity(cert, key)?;
+    }
+    let mut target_url = args.target_url;
+    if let Some(name) = args.target_sni {
+        (tls, target_url) = tls.sni(target_url, name)?;
+    }
+    let clients = Clients::new(tls);
+    let probe_client = clients.get(None)?;
+
+    let probe_url = args
+        .target_health_path
+        .map(|path| {
+            let mut url = reqwest::Url::parse(&target_url.to_string())?;
+            url.set_path(&path);
+            anyhow::Ok(url)
+        })
+        .transpose()?;
+    let probe_interval = Duration::from_secs(args.target_health_interval);
+    let retry = retry::Policy {
+        max_attempts: args.retry_attempts,
+        backoff: Duration::from_millis(args.retry_backoff_ms),
+        max_backoff: Duration::from_millis(args.retry_max_backoff_ms),
+        statuses: args.retry_statuses,
+        errors: args.retry_errors,
+        budget: retry::Budget::new(args.retry_budget),
+    };
 
-    porcoc::start(
-        args.porcod_certs.map(load_certs).transpose()?,
-        args.porcod_url,
-        args.target_url,
-    )
-    .await
+    tokio::select! {
+        res = porcoc::start(
+            args.porcod_certs.map(load_certs).transpose()?,
+            args.porcod_url,
+            args.tunnel,
+            args.identity.unwrap_or_default(),
+            porcoc::Target {
+                url: target_url,
+                clients,
+                host_policy: args.host_policy,
+                timeouts: args.timeouts.unwrap_or_default(),
+                retry,
+                health: health.clone(),
+                hide_error_details: args.hide_error_details,
+            },
+        ) => res,
+        res = async {
+            match args.metrics_addr {
+                Some(addr) => Ok(common::metrics::serve(addr).await?),
+                None => std::future::pending().await,
+            }
+        } => res,
+        res = async {
+            match args.admin_addr {
+                Some(addr) => Ok(common::health::serve(addr, health.clone()).await?),
+                None => std::future::pending().await,
+            }
+        } => res,
+        res = async {
+            match probe_url {
+                Some(url) => porcoc::health::probe(probe_client, url, probe_interval, &health).await,
+                None => std::future::pending().await,
+            }
+        } => res,
+    }
 }
 
 fn load_certs(filename: PathBuf) -> io::Result<Certificate> {
@@ -0,0 +1,67 @@
+use std::sync::LazyLock;
+
+use prometheus::{
+    register_histogram, register_int_counter, register_int_counter_vec, register_int_gauge,
+    Histogram, IntCounter, IntCounterVec, IntGauge,
+};
+
+// metrics are registered on first use, and the names are constants, so registration can't fail
+
+pub static BACKEND_RESPONSES: LazyLock<IntCounterVec> = LazyLock::new(|| {
+    register_int_counter_vec!(
+        "porcoc_backend_responses_total",
+        "Responses received from the target",
+        &["method", "status"]
+    )
+    .unwrap()
+});
+
+pub static BACKEND_DURATION: LazyLock<Histogram> = LazyLock::new(|| {
+    register_histogram!(
+        "porcoc_backend_duration_seconds",
+        "Time to receive a whole response from the target"
+    )
+    .unwrap()
+});
+
+pub static DISPATCH_ERRORS: LazyLock<IntCounterVec> = LazyLock::new(|| {
+    register_int_counter_vec!(
+        "porcoc_dispatch_errors_total",
+        "Requests failed before getting a response from the target",
+        &["kind"]
+    )
+    .unwrap()
+});
+
+pub static RECONNECTS: LazyLock<IntCounter> = LazyLock::new(|| {
+    register_int_counter!("porcoc_reconnects_total", "Reconnections to porcod").unwrap()
+});
+
+pub static CONNECTED: LazyLock<IntGauge> = LazyLock::new(|| {
+    register_int_gauge!(
+        "porcoc_tunnel_connected",
+        "Whether the tunnel to porcod is established"
+    )
+    .unwrap()
+});
+
+pub static IN_FLIGHT: LazyLock<IntGauge> = LazyLock::new(|| {
+    register_int_gauge!("porcoc_in_flight_requests", "Requests being served").unwrap()
+});
+
+pub static TARGET_HEALTHY: LazyLock<IntGauge> = LazyLock::new(|| {
+    register_int_gauge!(
+        "porcoc_target_healthy",
+        "Whether the target passes its probe and the circuit breaker is closed"
+    )
+    .unwrap()
+});
+
+pub static RETRIES: LazyLock<IntCounterVec> = LazyLock::new(|| {
+    register_int_counter_vec!(
+        "porcoc_retries_total",
+        "Calls to the target retried, by the failure causing them",
+        &["reason"]
+    )
+    .unwrap()
+});
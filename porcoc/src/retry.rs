@@ -0,0 +1,101 @@
+use std::{sync::Mutex, time::Duration};
+
+use clap::ValueEnum;
+use reqwest::{header::HeaderName, Method, StatusCode};
+
+pub const IDEMPOTENCY_KEY: HeaderName = HeaderName::from_static("idempotency-key");
+
+// retries available before any request has been seen, and at most
+const BUDGET_INITIAL: f64 = 10.0;
+const BUDGET_MAX: f64 = 100.0;
+
+/// Failures happening before the target answers
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum ErrorKind {
+    /// the connection couldn't be established
+    Connect,
+    /// the call timed out
+    Timeout,
+    /// the connection broke while sending the request or reading the response head
+    Transport,
+}
+
+impl ErrorKind {
+    pub fn of(err: &reqwest::Error) -> Self {
+        if err.is_connect() {
+            Self::Connect
+        } else if err.is_timeout() {
+            Self::Timeout
+        } else {
+            Self::Transport
+        }
+    }
+
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Self::Connect => "connect",
+            Self::Timeout => "timeout",
+            Self::Transport => "transport",
+        }
+    }
+}
+
+/// When and how failed calls to the target are retried
+#[derive(Debug)]
+pub struct Policy {
+    /// attempts per request, including the first one
+    pub max_attempts: u32,
+    /// wait before the first retry, doubling at each further one
+    pub backoff: Duration,
+    pub max_backoff: Duration,
+    pub statuses: Vec<StatusCode>,
+    pub errors: Vec<ErrorKind>,
+    pub budget: Budget,
+}
+
+impl Policy {
+    /// Retrying must not change the outcome, which is true of idempotent methods, or when the caller says so
+    pub fn applies(&self, method: &Method, has_idempotency_key: bool) -> bool {
+        self.max_attempts > 1 && (method.is_idempotent() || has_idempotency_key)
+    }
+
+    /// Wait before retrying after `attempt` failed
+    pub fn backoff(&self, attempt: u32) -> Duration {
+        self.backoff
+            .saturating_mul(2u32.saturating_pow(attempt.saturating_sub(1)))
+            .min(self.max_backoff)
+    }
+}
+
+/// Caps retries to a share of the requests, so that a struggling target isn't flooded with them
+#[derive(Debug)]
+pub struct Budget {
+    /// retries earned by each request
+    ratio: f64,
+    balance: Mutex<f64>,
+}
+
+impl Budget {
+    pub fn new(ratio: f64) -> Self {
+        Self {
+            ratio,
+            balance: Mutex::new(BUDGET_INITIAL),
+        }
+    }
+
+    /// Called once per request
+    pub fn deposit(&self) {
+        let mut balance = self.balance.lock().unwrap();
+        *balance = (*balance + self.ratio).min(BUDGET_MAX);
+    }
+
+    /// Whether a retry can be made, spending it
+    pub fn withdraw(&self) -> bool {
+        let mut balance = self.balance.lock().unwrap();
+        if *balance < 1.0 {
+            return false;
+        }
+        *balance -= 1.0;
+        true
+    }
+}
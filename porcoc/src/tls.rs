@@ -0,0 +1,108 @@
+use std::{fs, path::Path, sync::Arc};
+
+use anyhow::Context;
+use reqwest::{
+    dns::{Addrs, Name, Resolve, Resolving},
+    Certificate, ClientBuilder, Identity,
+};
+use tonic::transport::Uri;
+
+/// How porcoc secures its connections to the target
+#[derive(Debug, Clone, Default)]
+pub struct Tls {
+    /// trusted besides the system roots
+    ca: Vec<Certificate>,
+    /// client certificate, for targets requiring mTLS
+    identity: Option<Identity>,
+    /// SNI name, and the target url host it stands for
+    sni: Option<(String, String)>,
+    insecure_skip_verify: bool,
+}
+
+impl Tls {
+    /// Trusts the certificates of a PEM bundle
+    pub fn ca(mut self, path: &Path) -> anyhow::Result<Self> {
+        let pem = fs::read(path).with_context(|| format!("reading {}", path.display()))?;
+        self.ca = Certificate::from_pem_bundle(&pem)?;
+        Ok(self)
+    }
+
+    /// Presents a PEM certificate chain and its private key
+    pub fn identity(mut self, cert: &Path, key: &Path) -> anyhow::Result<Self> {
+        let mut pem = fs::read(cert).with_context(|| format!("reading {}", cert.display()))?;
+        pem.push(b'\n');
+        pem.extend(fs::read(key).with_context(|| format!("reading {}", key.display()))?);
+        self.identity = Some(Identity::from_pem(&pem)?);
+        Ok(self)
+    }
+
+    /// Calls the target as `name`, returning its url with the host replaced
+    ///
+    /// Connections still go to the original host, resolved each time
+    pub fn sni(mut self, url: Uri, name: String) -> anyhow::Result<(Self, Uri)> {
+        let host = url.host().context("target url without host")?.to_owned();
+        let mut parts = url.into_parts();
+        let authority = parts
+            .authority
+            .as_ref()
+            .context("target url without host")?;
+        parts.authority = Some(match authority.port() {
+            Some(port) => format!("{name}:{port}").parse()?,
+            None => name.parse()?,
+        });
+        let url = Uri::from_parts(parts)?;
+        self.sni = Some((name, host));
+        Ok((self, url))
+    }
+
+    /// The target url host replaced by the SNI name, if any
+    pub(crate) fn sni_host(&self) -> Option<&str> {
+        self.sni.as_ref().map(|(_, host)| host.as_str())
+    }
+
+    /// Skips verifying the target certificate, for lab environments only
+    pub fn insecure_skip_verify(mut self, insecure_skip_verify: bool) -> Self {
+        self.insecure_skip_verify = insecure_skip_verify;
+        self
+    }
+
+    pub(crate) fn apply(&self, mut builder: ClientBuilder) -> ClientBuilder {
+        for ca in &self.ca {
+            builder = builder.add_root_certificate(ca.clone());
+        }
+        if let Some(identity) = &self.identity {
+            builder = builder.identity(identity.clone());
+        }
+        if let Some((name, host)) = &self.sni {
+            builder = builder.dns_resolver(Arc::new(SniResolver {
+                name: name.clone(),
+                host: host.trim_matches(['[', ']']).to_owned(),
+            }));
+        }
+        builder.danger_accept_invalid_certs(self.insecure_skip_verify)
+    }
+}
+
+/// Resolves the SNI name as the host it stands for, other names as they are
+#[derive(Debug)]
+struct SniResolver {
+    name: String,
+    host: String,
+}
+
+impl Resolve for SniResolver {
+    fn resolve(&self, name: Name) -> Resolving {
+        let host = if name.as_str().eq_ignore_ascii_case(&self.name) {
+            self.host.clone()
+        } else {
+            name.as_str().to_owned()
+        };
+        Box::pin(async move {
+            // the client sets the port of the url
+            let addrs = tokio::net::lookup_host((host.as_str(), 0))
+                .await?
+                .collect::<Vec<_>>();
+            Ok(Box::new(addrs.into_iter()) as Addrs)
+        })
+    }
+}
@@ -0,0 +1,170 @@
+use std::{
+    io::{self, Write},
+    net::IpAddr,
+    path::Path,
+    time::Duration,
+};
+
+use clap::ValueEnum;
+use http::{Method, Uri, Version};
+use time::{format_description::well_known::Rfc3339, macros::format_description, OffsetDateTime};
+use tracing::error;
+use tracing_appender::{
+    non_blocking::{NonBlocking, WorkerGuard},
+    rolling::{RollingFileAppender, Rotation},
+};
+
+/// Access log line format
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum Format {
+    /// Common Log Format, followed by porcod fields
+    Common,
+    /// Combined Log Format, followed by porcod fields
+    Combined,
+    /// a JSON object per line
+    Json,
+}
+
+/// How often the access log file is rotated
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum Rotate {
+    Minutely,
+    Hourly,
+    Daily,
+    Never,
+}
+
+/// A request as it appears in the access log
+#[derive(Debug)]
+pub struct Entry<'a> {
+    pub client_ip: IpAddr,
+    pub method: &'a Method,
+    pub uri: &'a Uri,
+    pub version: Version,
+    pub referer: Option<&'a str>,
+    pub user_agent: Option<&'a str>,
+    /// status code, or the reason there's none
+    pub status: &'a str,
+    pub bytes: u64,
+    pub latency: Duration,
+    pub tunnel: &'a str,
+    pub session: Option<&'a str>,
+    pub request_id: &'a str,
+}
+
+/// Writes access log lines off the request path
+#[derive(Debug)]
+pub struct AccessLog {
+    format: Format,
+    writer: NonBlocking,
+    // flushes pending lines on drop
+    _guard: WorkerGuard,
+}
+
+impl AccessLog {
+    pub fn stdout(format: Format) -> Self {
+        Self::new(format, io::stdout())
+    }
+
+    /// Logs to `file`, rotated with a timestamp suffix
+    pub fn file(format: Format, file: &Path, rotate: Rotate) -> io::Result<Self> {
+        let rotation = match rotate {
+            Rotate::Minutely => Rotation::MINUTELY,
+            Rotate::Hourly => Rotation::HOURLY,
+            Rotate::Daily => Rotation::DAILY,
+            Rotate::Never => Rotation::NEVER,
+        };
+        let directory = file
+            .parent()
+            .filter(|parent| !parent.as_os_str().is_empty())
+            .unwrap_or(Path::new("."));
+        let prefix = file.file_name().ok_or_else(|| {
+            io::Error::new(io::ErrorKind::InvalidInput, "access log file has no name")
+        })?;
+        let appender = RollingFileAppender::builder()
+            .rotation(rotation)
+            .filename_prefix(prefix.to_string_lossy())
+            .build(directory)
+            .map_err(io::Error::other)?;
+        Ok(Self::new(format, appender))
+    }
+
+    fn new<W: Write + Send + 'static>(format: Format, writer: W) -> Self {
+        let (writer, guard) = tracing_appender::non_blocking(writer);
+        Self {
+            format,
+            writer,
+            _guard: guard,
+        }
+    }
+
+    pub fn log(&self, entry: &Entry<'_>) {
+        let now = OffsetDateTime::now_utc();
+        let mut line = match self.format {
+            Format::Common | Format::Combined => {
+                let mut line = format!(
+                    "{} - - [{}] \"{} {} {:?}\" {} {}",
+                    entry.client_ip,
+                    now.format(format_description!(
+                        "[day]/[month repr:short]/[year]:[hour]:[minute]:[second] +0000"
+                    ))
+                    .unwrap_or_default(),
+                    entry.method,
+                    entry.uri,
+                    entry.version,
+                    entry.status,
+                    entry.bytes,
+                );
+                if self.format == Format::Combined {
+                    line.push_str(&format!(
+                        " \"{}\" \"{}\"",
+                        escape(entry.referer.unwrap_or("-")),
+                        escape(entry.user_agent.unwrap_or("-")),
+                    ));
+                }
+                line.push_str(&format!(
+                    " rt={:.3} tunnel={} session={} id={}",
+                    entry.latency.as_secs_f64(),
+                    or_dash(entry.tunnel),
+                    entry.session.unwrap_or("-"),
+                    entry.request_id,
+                ));
+                line
+            }
+            Format::Json => serde_json::json!({
+                "time": now.format(&Rfc3339).unwrap_or_default(),
+                "client_ip": entry.client_ip,
+                "method": entry.method.as_str(),
+                "uri": entry.uri.to_string(),
+                "version": format!("{:?}", entry.version),
+                "status": entry.status,
+                "bytes": entry.bytes,
+                "latency": entry.latency.as_secs_f64(),
+                "referer": entry.referer,
+                "user_agent": entry.user_agent,
+                "tunnel": (!entry.tunnel.is_empty()).then_some(entry.tunnel),
+                "session": entry.session,
+                "request_id": entry.request_id,
+            })
+            .to_string(),
+        };
+        line.push('\n');
+
+        if let Err(err) = self.writer.clone().write_all(line.as_bytes()) {
+            error!("Failed to write access log: {err}");
+        }
+    }
+}
+
+fn or_dash(value: &str) -> &str {
+    if value.is_empty() {
+        "-"
+    } else {
+        value
+    }
+}
+
+// quoted fields can't break out of their quotes
+fn escape(value: &str) -> String {
+    value.replace('\\', "\\\\").replace('"', "\\\"")
+}
@@ -0,0 +1,214 @@
+use std::{
+    net::{AddrParseError, IpAddr},
+    str::FromStr,
+};
+
+use common::headers::X_FORWARDED_FOR;
+use http::HeaderMap;
+use ipnet::IpNet;
+
+/// CIDR based allow and deny lists
+#[derive(Debug, Clone, Default)]
+pub struct Acl {
+    /// when not empty, only these networks are allowed
+    pub allow: Vec<IpNet>,
+    /// networks never allowed
+    pub deny: Vec<IpNet>,
+}
+
+impl Acl {
+    /// Deny wins over allow
+    pub fn allows(&self, ip: IpAddr) -> bool {
+        (self.allow.is_empty() || contains(&self.allow, ip)) && !contains(&self.deny, ip)
+    }
+}
+
+pub fn contains(nets: &[IpNet], ip: IpAddr) -> bool {
+    nets.iter().any(|net| net.contains(&ip))
+}
+
+/// Parses a CIDR, plain addresses are single host networks
+pub fn parse_net(value: &str) -> Result<IpNet, AddrParseError> {
+    IpNet::from_str(value).or_else(|_| IpAddr::from_str(value).map(IpNet::from))
+}
+
+/// Parses a `TUNNEL=CIDR` pair
+pub fn parse_tunnel_net(value: &str) -> Result<(String, IpNet), String> {
+    let (tunnel, net) = value
+        .split_once('=')
+        .ok_or_else(|| "expected `TUNNEL=CIDR`".to_owned())?;
+    Ok((
+        tunnel.to_owned(),
+        parse_net(net).map_err(|err| err.to_string())?,
+    ))
+}
+
+/// Resolves the caller address, walking `X-Forwarded-For` from the right while the hops are trusted proxies
+pub fn client_ip(peer: IpAddr, headers: &HeaderMap, trusted_proxies: &[IpNet]) -> IpAddr {
+    let mut client = peer;
+    if !contains(trusted_proxies, client) {
+        return client;
+    }
+
+    let hops = headers
+        .get_all(X_FORWARDED_FOR)
+        .iter()
+        .flat_map(|value| match value.to_str() {
+            Ok(value) => value.split(',').map(Some).collect(),
+            Err(_) => vec![None],
+        })
+        .collect::<Vec<_>>();
+    for hop in hops.into_iter().rev() {
+        // a malformed hop can't be trusted to lead anywhere
+        let Some(Ok(ip)) = hop.map(|hop| IpAddr::from_str(hop.trim())) else {
+            break;
+        };
+        client = ip;
+        if !contains(trusted_proxies, client) {
+            break;
+        }
+    }
+    client
+}
+
+#[cfg(test)]
+mod tests {
+    use http::HeaderValue;
+
+    use super::*;
+
+    fn nets(values: &[&str]) -> Vec<IpNet> {
+        values
+            .iter()
+            .map(|value| parse_net(value).unwrap())
+            .collect()
+    }
+
+    fn ip(value: &str) -> IpAddr {
+        value.parse().unwrap()
+    }
+
+    fn forwarded_for(values: &[&str]) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        for value in values {
+            headers.append(X_FORWARDED_FOR, HeaderValue::from_str(value).unwrap());
+        }
+        headers
+    }
+
+    #[test]
+    fn acl() {
+        let acl = Acl {
+            allow: nets(&["10.0.0.0/8", "::1"]),
+            deny: nets(&["10.0.0.1"]),
+        };
+        assert!(acl.allows(ip("10.2.3.4")));
+        assert!(acl.allows(ip("::1")));
+        assert!(!acl.allows(ip("10.0.0.1")));
+        assert!(!acl.allows(ip("192.168.1.1")));
+        assert!(Acl::default().allows(ip("192.168.1.1")));
+
+        assert_eq!(
+            parse_tunnel_net("api=10.0.0.0/8").unwrap(),
+            ("api".to_owned(), nets(&["10.0.0.0/8"])[0])
+        );
+        assert!(parse_tunnel_net("10.0.0.0/8").is_err());
+        assert!(parse_tunnel_net("api=10.0.0.0/33").is_err());
+    }
+
+    #[test]
+    fn untrusted_peer() {
+        let headers = forwarded_for(&["1.1.1.1"]);
+        let trusted = nets(&["10.0.0.0/8"]);
+        assert_eq!(
+            client_ip(ip("192.168.1.1"), &headers, &trusted),
+            ip("192.168.1.1")
+        );
+        assert_eq!(client_ip(ip("10.0.0.2"), &headers, &[]), ip("10.0.0.2"));
+    }
+
+    #[test]
+    fn trusted_chain() {
+        let trusted = nets(&["10.0.0.0/8"]);
+        let headers = forwarded_for(&["1.1.1.1, 10.0.0.3", "10.0.0.4"]);
+        assert_eq!(client_ip(ip("10.0.0.2"), &headers, &trusted), ip("1.1.1.1"));
+
+        // the caller may forge the left part, only the first untrusted hop from the right counts
+        let headers = forwarded_for(&["6.6.6.6, 1.1.1.1, 10.0.0.3"]);
+        assert_eq!(client_ip(ip("10.0.0.2"), &headers, &trusted), ip("1.1.1.1"));
+
+        // every hop trusted, the leftmost one is the best guess
+        let headers = forwarded_for(&["10.0.0.5, 10.0.0.3"]);
+        assert_eq!(
+            client_ip(ip("10.0.0.2"), &headers, &trusted),
+            ip("10.0.0.5")
+        );
+
+        // no header, the proxy itself is the caller
+        assert_eq!(
+            client_ip(ip("10.0.0.2"), &HeaderMap::new(), &trusted),
+            ip("10.0.0.2")
+        );
+    }
+
+    #[test]
+    fn untrusted_hop() {
+        let trusted = nets(&["10.0.0.0/8"]);
+        let headers = forwarded_for(&["10.0.0.5, 2.2.2.2, 10.0.0.3"]);
+        assert_eq!(client_ip(ip("10.0.0.2"), &headers, &trusted), ip("2.2.2.2"));
+    }
+
+    #[test]
+    fn malformed_hops() {
+        let trusted = nets(&["10.0.0.0/8"]);
+        for value in [
+            "1.1.1.1, nonsense",
+            "1.1.1.1, 2.2.2.2:80",
+            "1.1.1.1,",
+            "1.1.1.1, unknown",
+        ] {
+            let headers = forwarded_for(&[value]);
+            assert_eq!(
+                client_ip(ip("10.0.0.2"), &headers, &trusted),
+                ip("10.0.0.2"),
+                "{value}"
+            );
+        }
+        // the walk stops at the last good hop
+        let headers = forwarded_for(&["1.1.1.1, garbage, 10.0.0.3"]);
+        assert_eq!(
+            client_ip(ip("10.0.0.2"), &headers, &trusted),
+            ip("10.0.0.3")
+        );
+
+        // values that aren't text stop it too
+        let mut headers = forwarded_for(&["1.1.1.1"]);
+        headers.append(X_FORWARDED_FOR, HeaderValue::from_bytes(b"\xff").unwrap());
+        headers.append(X_FORWARDED_FOR, HeaderValue::from_static("10.0.0.3"));
+        assert_eq!(
+            client_ip(ip("10.0.0.2"), &headers, &trusted),
+            ip("10.0.0.3")
+        );
+    }
+
+    #[test]
+    fn ipv6() {
+        let trusted = nets(&["fd00::/8", "10.0.0.0/8"]);
+        let headers = forwarded_for(&["2001:db8::1, fd00::3"]);
+        assert_eq!(
+            client_ip(ip("fd00::2"), &headers, &trusted),
+            ip("2001:db8::1")
+        );
+
+        // mixed families
+        let headers = forwarded_for(&["2001:db8::1, 10.0.0.3"]);
+        assert_eq!(
+            client_ip(ip("fd00::2"), &headers, &trusted),
+            ip("2001:db8::1")
+        );
+
+        // brackets and ports aren't part of X-Forwarded-For
+        let headers = forwarded_for(&["[2001:db8::1]:443"]);
+        assert_eq!(client_ip(ip("fd00::2"), &headers, &trusted), ip("fd00::2"));
+    }
+}
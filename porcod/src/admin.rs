@@ -0,0 +1,189 @@
+use std::{convert::Infallible, io, net::SocketAddr, sync::Arc};
+
+use common::body::Body;
+use http::{
+    header::{AUTHORIZATION, CONTENT_TYPE, WWW_AUTHENTICATE},
+    HeaderValue, Method, Request, Response, StatusCode,
+};
+use hyper::{
+    body::{Bytes, Incoming},
+    service::service_fn,
+};
+use hyper_util::{
+    rt::{TokioExecutor, TokioIo},
+    server::conn::auto,
+};
+use serde_json::{json, Value};
+use time::format_description::well_known::Rfc3339;
+use tokio::net::TcpListener;
+use tracing::{debug, error};
+
+use crate::{grpc::Admin, health::Health};
+
+/// What the admin listener serves
+#[derive(Debug)]
+pub struct Config {
+    pub health: Arc<Health>,
+    pub admin: Admin,
+    /// bearer token required by the API, which is disabled without one
+    pub token: Option<String>,
+}
+
+/// Serves `/healthz` and `/readyz`, and the admin API under `/api/`
+pub async fn serve(addr: SocketAddr, config: Config) -> io::Result<()> {
+    let listener = TcpListener::bind(&addr).await?;
+    debug!("Admin listening on http://{}", addr);
+
+    let config = Arc::new(config);
+    loop {
+        let (stream, _) = listener.accept().await?;
+        let config = config.clone();
+        tokio::spawn(async move {
+            if let Err(err) = auto::Builder::new(TokioExecutor::new())
+                .serve_connection(
+                    TokioIo::new(stream),
+                    service_fn(move |req| handle(req, config.clone())),
+                )
+                .await
+            {
+                error!("Failed to serve admin: {err}");
+            }
+        });
+    }
+}
+
+async fn handle(req: Request<Incoming>, config: Arc<Config>) -> Result<Response<Body>, Infallible> {
+    if let Some(response) = common::health::respond(&req, config.health.as_ref()) {
+        return Ok(response);
+    }
+
+    let Some(path) = req.uri().path().strip_prefix("/api/") else {
+        return Ok(status(StatusCode::NOT_FOUND));
+    };
+    let Some(token) = &config.token else {
+        return Ok(status(StatusCode::NOT_FOUND));
+    };
+    if !authorized(&req, token) {
+        let mut response = status(StatusCode::UNAUTHORIZED);
+        response
+            .headers_mut()
+            .insert(WWW_AUTHENTICATE, HeaderValue::from_static("Bearer"));
+        return Ok(response);
+    }
+
+    let admin = &config.admin;
+    let segments = path.split('/').collect::<Vec<_>>();
+    let response = match (req.method(), segments.as_slice()) {
+        (&Method::GET, ["sessions"]) => {
+            let sessions = admin.sessions().await;
+            json_response(
+                sessions
+                    .iter()
+                    .map(|session| {
+                        let since = session.connected_since.format(&Rfc3339);
+                        json!({
+                            "id": session.id,
+                            "tunnel": session.tunnel,
+                            "address": session.address.as_ref(),
+                            "identity": session.identity,
+                            "version": session.version,
+                            "connected_since": since.unwrap_or_default(),
+                            "in_flight": session.in_flight,
+                            "healthy": session.healthy,
+                        })
+                    })
+                    .collect(),
+            )
+        }
+        (&Method::DELETE, ["sessions", id]) => match id.parse() {
+            Ok(id) if admin.disconnect(id) => status(StatusCode::NO_CONTENT),
+            _ => status(StatusCode::NOT_FOUND),
+        },
+        (&Method::GET, ["requests"]) => {
+            let pending = admin.pending().await;
+            json_response(
+                pending
+                    .iter()
+                    .map(|pending| {
+                        json!({
+                            "id": pending.id,
+                            "request_id": pending.request_id,
+                            "tunnel": pending.tunnel,
+                            "method": pending.method,
+                            "uri": pending.uri,
+                            "session": pending.session,
+                            "age": pending.age.as_secs_f64(),
+                            "expires_in": pending.expires_in.as_secs_f64(),
+                        })
+                    })
+                    .collect(),
+            )
+        }
+        (&Method::GET, ["tunnels"]) => {
+            let tunnels = admin.tunnels().await;
+            json_response(
+                tunnels
+                    .iter()
+                    .map(|tunnel| {
+                        json!({
+                            "name": tunnel.name,
+                            "sessions": tunnel.sessions,
+                            "queued": tunnel.queued,
+                            "paused": tunnel.paused,
+                            "draining": tunnel.draining,
+                        })
+                    })
+                    .collect(),
+            )
+        }
+        (&Method::POST, ["tunnels", tunnel, action]) if !tunnel.is_empty() => match *action {
+            "pause" => {
+                admin.pause(tunnel).await;
+                status(StatusCode::NO_CONTENT)
+            }
+            "drain" => {
+                admin.drain(tunnel).await;
+                status(StatusCode::NO_CONTENT)
+            }
+            "resume" => {
+                admin.resume(tunnel).await;
+                status(StatusCode::NO_CONTENT)
+            }
+            _ => status(StatusCode::NOT_FOUND),
+        },
+        _ => status(StatusCode::NOT_FOUND),
+    };
+    Ok(response)
+}
+
+fn authorized<B>(req: &Request<B>, token: &str) -> bool {
+    let Some(provided) = req
+        .headers()
+        .get(AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "))
+    else {
+        return false;
+    };
+    // compares in constant time, not to leak how much of the token is right
+    provided.len() == token.len()
+        && provided
+            .bytes()
+            .zip(token.bytes())
+            .fold(0, |acc, (a, b)| acc | (a ^ b))
+            == 0
+}
+
+fn status(status: StatusCode) -> Response<Body> {
+    let mut response = Response::new(Body::default());
+    *response.status_mut() = status;
+    response
+}
+
+fn json_response(value: Value) -> Response<Body> {
+    let mut response = Response::new(Body::from(Bytes::from(value.to_string())));
+    response
+        .headers_mut()
+        .insert(CONTENT_TYPE, HeaderValue::from_static("application/json"));
+    response
+}
@@ -0,0 +1,354 @@
+use std::{collections::HashMap, fs, io, path::PathBuf, str::FromStr};
+
+use common::problem::{Problem, CONTENT_TYPE_PROBLEM};
+use http::{header::CONTENT_TYPE, HeaderValue, StatusCode, Version};
+
+/// Templates answered in place of problem details, by status
+#[derive(Debug, Default)]
+pub struct ErrorPages {
+    pages: HashMap<StatusCode, Templates>,
+}
+
+#[derive(Debug, Default)]
+struct Templates {
+    html: Option<String>,
+    json: Option<String>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Format {
+    Html,
+    Json,
+}
+
+impl Format {
+    fn content_type(self) -> &'static str {
+        match self {
+            Self::Html => "text/html; charset=utf-8",
+            Self::Json => "application/json",
+        }
+    }
+
+    /// Quality the caller gives to the format, 0 if it doesn't accept it
+    ///
+    /// The most specific matching range decides: the exact type, then `type/*`, then `*/*`
+    fn quality(self, accept: &str) -> f32 {
+        let (essence, subtype) = match self {
+            Self::Html => ("text/html", "text/*"),
+            Self::Json => ("application/json", "application/*"),
+        };
+        accept
+            .split(',')
+            .filter_map(|range| {
+                let mut params = range.split(';').map(str::trim);
+                let media = params.next()?.to_ascii_lowercase();
+                let specificity = if media == essence
+                    || (self == Self::Json
+                        && (media == CONTENT_TYPE_PROBLEM || media.ends_with("+json")))
+                {
+                    2
+                } else if media == subtype {
+                    1
+                } else if media == "*/*" {
+                    0
+                } else {
+                    return None;
+                };
+                // an invalid weight can't be trusted to accept anything
+                let q = params
+                    .find_map(|param| param.strip_prefix("q="))
+                    .map_or(Some(1.0), |q| {
+                        f32::from_str(q).ok().filter(|q| (0.0..=1.0).contains(q))
+                    })
+                    .unwrap_or(0.0);
+                Some((specificity, q))
+            })
+            .fold(
+                None,
+                |best: Option<(u8, f32)>, (specificity, q)| match best {
+                    Some((best_specificity, best_q))
+                        if best_specificity > specificity
+                            || (best_specificity == specificity && best_q >= q) =>
+                    {
+                        best
+                    }
+                    _ => Some((specificity, q)),
+                },
+            )
+            .map_or(0.0, |(_, q)| q)
+    }
+}
+
+impl ErrorPages {
+    /// Reads the templates, their format comes from the file extension
+    pub fn load(pages: Vec<(StatusCode, PathBuf)>) -> io::Result<Self> {
+        let mut error_pages = Self::default();
+        for (status, path) in pages {
+            let format = match path.extension().and_then(|ext| ext.to_str()) {
+                Some("html" | "htm") => Format::Html,
+                Some("json") => Format::Json,
+                _ => {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidInput,
+                        format!("{}: expected a .html or .json file", path.display()),
+                    ))
+                }
+            };
+            let template = fs::read_to_string(&path)?;
+            let templates = error_pages.pages.entry(status).or_default();
+            match format {
+                Format::Html => templates.html = Some(template),
+                Format::Json => templates.json = Some(template),
+            }
+        }
+        Ok(error_pages)
+    }
+
+    /// Renders `problem` with the template of its status the caller accepts best, as problem details if there's none
+    pub fn render(
+        &self,
+        problem: Problem,
+        accept: Option<&HeaderValue>,
+        request_id: &str,
+        details: bool,
+    ) -> common::OutgoingResponse {
+        let Some(templates) = self.pages.get(&problem.status) else {
+            return problem.into_response(request_id, details);
+        };
+        // without preferences, machine readable errors come first
+        let accept = accept
+            .and_then(|accept| accept.to_str().ok())
+            .unwrap_or("*/*");
+        let (html, json) = (Format::Html.quality(accept), Format::Json.quality(accept));
+        let (format, template) = match (&templates.html, &templates.json) {
+            (Some(template), _) if html > json => (Format::Html, template),
+            (_, Some(template)) if json > 0.0 => (Format::Json, template),
+            (Some(template), _) if html > 0.0 => (Format::Html, template),
+            _ => return problem.into_response(request_id, details),
+        };
+
+        let detail = problem.detail.as_deref().filter(|_| details);
+        let vars = [
+            ("status", problem.status.as_str()),
+            (
+                "reason",
+                problem.status.canonical_reason().unwrap_or_default(),
+            ),
+            ("title", problem.kind.title()),
+            ("kind", problem.kind.as_str()),
+            ("detail", detail.unwrap_or_default()),
+            ("request_id", request_id),
+        ];
+        // a single pass, so that values containing `{{name}}` aren't expanded
+        let mut body = String::with_capacity(template.len());
+        let mut rest = template.as_str();
+        while let Some(start) = rest.find("{{") {
+            body.push_str(&rest[..start]);
+            rest = &rest[start..];
+            let value = rest.find("}}").and_then(|end| {
+                let (_, value) = vars.iter().find(|(name, _)| *name == &rest[2..end])?;
+                Some((end, value))
+            });
+            match value {
+                Some((end, value)) => {
+                    body.push_str(&escape(format, value));
+                    rest = &rest[end + 2..];
+                }
+                None => {
+                    body.push_str("{{");
+                    rest = &rest[2..];
+                }
+            }
+        }
+        body.push_str(rest);
+
+        common::OutgoingResponse {
+            status: problem.status,
+            version: Version::default(),
+            headers: vec![(
+                CONTENT_TYPE,
+                HeaderValue::from_static(format.content_type()),
+            )],
+            body: body.into(),
+            trailers: vec![],
+        }
+    }
+}
+
+/// Makes a value safe to put in a template, JSON values are meant to be placed between quotes
+fn escape(format: Format, value: &str) -> String {
+    match format {
+        Format::Html => value
+            .replace('&', "&amp;")
+            .replace('<', "&lt;")
+            .replace('>', "&gt;")
+            .replace('"', "&quot;")
+            .replace('\'', "&#39;"),
+        Format::Json => {
+            let quoted = serde_json::Value::from(value).to_string();
+            quoted[1..quoted.len() - 1].to_owned()
+        }
+    }
+}
+
+/// Parses a `STATUS=FILE` pair
+pub fn parse(value: &str) -> Result<(StatusCode, PathBuf), String> {
+    let (status, path) = value
+        .split_once('=')
+        .ok_or_else(|| "expected `STATUS=FILE`".to_owned())?;
+    Ok((
+        StatusCode::from_str(status).map_err(|err| err.to_string())?,
+        PathBuf::from(path),
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use common::problem::Kind;
+
+    use super::*;
+
+    fn pages() -> ErrorPages {
+        let mut pages = ErrorPages::default();
+        pages.pages.insert(
+            StatusCode::SERVICE_UNAVAILABLE,
+            Templates {
+                html: Some("<p>{{status}} {{reason}}: {{detail}}</p>".to_owned()),
+                json: Some(
+                    r#"{"error":"{{kind}}","detail":"{{detail}}","id":"{{request_id}}"}"#
+                        .to_owned(),
+                ),
+            },
+        );
+        pages.pages.insert(
+            StatusCode::GATEWAY_TIMEOUT,
+            Templates {
+                html: Some("<p>{{title}}</p>".to_owned()),
+                json: None,
+            },
+        );
+        pages
+    }
+
+    /// The content type answered to `accept`
+    fn negotiate(problem: Problem, accept: Option<&str>) -> String {
+        let accept = accept.map(|accept| HeaderValue::from_str(accept).unwrap());
+        let response = pages().render(problem, accept.as_ref(), "id", true);
+        response.headers[0].1.to_str().unwrap().to_owned()
+    }
+
+    #[test]
+    fn quality() {
+        assert_eq!(Format::Html.quality("text/html"), 1.0);
+        assert_eq!(Format::Html.quality("TEXT/HTML;q=0.5"), 0.5);
+        assert_eq!(Format::Html.quality("text/*; q=0.3, text/html;q=0.7"), 0.7);
+        assert_eq!(Format::Html.quality("*/*;q=0.1"), 0.1);
+        assert_eq!(Format::Html.quality("application/json"), 0.0);
+        assert_eq!(Format::Html.quality("text/html;q=nope"), 0.0);
+        assert_eq!(Format::Html.quality("text/html;q=2"), 0.0);
+        // the most specific range wins, whatever the weights
+        assert_eq!(Format::Html.quality("text/*;q=0.7, text/html;q=0.3"), 0.3);
+        assert_eq!(Format::Html.quality("text/html;q=0, */*"), 0.0);
+        assert_eq!(Format::Html.quality("*/*, text/*;q=0"), 0.0);
+        assert_eq!(Format::Html.quality("*/*;q=0.2, text/*;q=0.6"), 0.6);
+        assert_eq!(Format::Json.quality("application/json;q=0, */*"), 0.0);
+        assert_eq!(Format::Json.quality("application/problem+json"), 1.0);
+        assert_eq!(Format::Json.quality("application/vnd.api+json;q=0.4"), 0.4);
+        assert_eq!(Format::Json.quality("application/*;q=0.2"), 0.2);
+        assert_eq!(Format::Json.quality("text/html, image/*"), 0.0);
+    }
+
+    #[test]
+    fn negotiation() {
+        let problem = || Problem::new(Kind::Unavailable);
+        let html = Format::Html.content_type();
+        let json = Format::Json.content_type();
+        // without preferences, or on ties, JSON comes first
+        assert_eq!(negotiate(problem(), None), json);
+        assert_eq!(negotiate(problem(), Some("*/*")), json);
+        assert_eq!(
+            negotiate(problem(), Some("text/html, application/json")),
+            json
+        );
+        assert_eq!(negotiate(problem(), Some("text/html")), html);
+        assert_eq!(
+            negotiate(problem(), Some("text/html;q=0.9, application/json;q=0.8")),
+            html
+        );
+        assert_eq!(negotiate(problem(), Some("text/html, */*;q=0.1")), html);
+        assert_eq!(negotiate(problem(), Some("application/*")), json);
+        assert_eq!(negotiate(problem(), Some("text/html;q=0, */*")), json);
+        assert_eq!(
+            negotiate(problem(), Some("text/*;q=0.7, text/html;q=0.3, */*;q=0.5")),
+            json
+        );
+        // unacceptable formats fall back to problem details
+        assert_eq!(
+            negotiate(problem(), Some("image/png")),
+            CONTENT_TYPE_PROBLEM
+        );
+        assert_eq!(
+            negotiate(problem(), Some("text/html;q=0, application/json;q=0")),
+            CONTENT_TYPE_PROBLEM
+        );
+        // only the templates of the status count
+        let timeout = || Problem::new(Kind::Timeout);
+        assert_eq!(negotiate(timeout(), None), html);
+        assert_eq!(
+            negotiate(timeout(), Some("application/json")),
+            CONTENT_TYPE_PROBLEM
+        );
+        let rejected = Problem::new(Kind::Rejected);
+        assert_eq!(negotiate(rejected, Some("text/html")), CONTENT_TYPE_PROBLEM);
+    }
+
+    #[test]
+    fn escaping() {
+        let problem = Problem::new(Kind::Unavailable).with_detail(r#"<b>"quoted" & \ 'x'</b>"#);
+        let accept = HeaderValue::from_static("text/html");
+        let response = pages().render(problem.clone(), Some(&accept), "id", true);
+        assert_eq!(
+            response.body,
+            "<p>503 Service Unavailable: &lt;b&gt;&quot;quoted&quot; &amp; \\ &#39;x&#39;&lt;/b&gt;</p>"
+        );
+
+        let response = pages().render(problem.clone(), None, "a\"b", true);
+        let body: serde_json::Value = serde_json::from_slice(&response.body).unwrap();
+        assert_eq!(body["error"], "unavailable");
+        assert_eq!(body["detail"], r#"<b>"quoted" & \ 'x'</b>"#);
+        assert_eq!(body["id"], "a\"b");
+
+        // hidden details render empty
+        let response = pages().render(problem, None, "id", false);
+        let body: serde_json::Value = serde_json::from_slice(&response.body).unwrap();
+        assert_eq!(body["detail"], "");
+    }
+
+    #[test]
+    fn single_pass() {
+        let mut pages = ErrorPages::default();
+        pages.pages.insert(
+            StatusCode::SERVICE_UNAVAILABLE,
+            Templates {
+                html: Some("{{status}} {{unknown}} {{detail}} {{ {{kind}}}}".to_owned()),
+                json: None,
+            },
+        );
+        let problem = Problem::new(Kind::Unavailable).with_detail("{{status}} {{request_id}}");
+        let response = pages.render(problem, None, "id", true);
+        assert_eq!(
+            response.body,
+            "503 {{unknown}} {{status}} {{request_id}} {{ unavailable}}"
+        );
+    }
+
+    #[test]
+    fn parse_pages() {
+        let (status, path) = parse("503=/etc/porcod/503.html").unwrap();
+        assert_eq!(status, StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(path, PathBuf::from("/etc/porcod/503.html"));
+        assert!(parse("503").is_err());
+        assert!(parse("5xx=page.html").is_err());
+        assert!(ErrorPages::load(vec![(StatusCode::NOT_FOUND, "page.txt".into())]).is_err());
+    }
+}
@@ -0,0 +1,402 @@
+use std::{net::IpAddr, str::FromStr};
+
+use common::timeouts::Timeouts;
+use http::{header::HOST, uri::Authority, HeaderName, Method, Request};
+use ipnet::IpNet;
+use regex::Regex;
+
+use crate::acl;
+
+/// A webserver filter rule
+///
+/// Rules are written as an optional `allow` (default) or `deny` action, followed by whitespace separated conditions,
+/// all of which must match for the rule to apply:
+/// - `path=REGEX` matches the request path, a bare `REGEX` is accepted too
+/// - `method=GET,HEAD` matches any of the given methods
+/// - `host=REGEX` matches the requested host, without port
+/// - `header=NAME` requires the header, `header=NAME:REGEX` requires a matching value
+/// - `query=NAME` requires the query parameter, `query=NAME:REGEX` requires a matching value
+/// - `source=CIDR,CIDR` matches the caller address
+///
+/// Allow rules can also pick the tunnel serving the request with `tunnel=NAME`, otherwise the default tunnel is used,
+/// and set its timeouts with `timeouts=TIMEOUTS`, see [`Timeouts`].
+///
+/// e.g. `deny path=^/admin`, `allow method=GET,HEAD path=^/api/ source=10.0.0.0/8 tunnel=api`,
+/// `path=^/export timeouts=ttfb=2m,total=5m`
+#[derive(Debug, Clone)]
+pub struct Filter {
+    action: Action,
+    conditions: Vec<Condition>,
+    tunnel: Option<String>,
+    timeouts: Timeouts,
+}
+
+/// Where an allowed request goes
+#[derive(Debug, Clone, Copy)]
+pub struct Route<'a> {
+    pub tunnel: &'a str,
+    /// set by the filter, the tunnel and global ones apply otherwise
+    pub timeouts: Timeouts,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Action {
+    Allow,
+    Deny,
+}
+
+#[derive(Debug, Clone)]
+enum Condition {
+    Path(Regex),
+    Method(Vec<Method>),
+    Host(Regex),
+    Header(HeaderName, Option<Regex>),
+    Query(String, Option<Regex>),
+    Source(Vec<IpNet>),
+}
+
+impl Filter {
+    fn matches<B>(&self, req: &Request<B>, remote_addr: IpAddr) -> bool {
+        self.conditions
+            .iter()
+            .all(|condition| condition.matches(req, remote_addr))
+    }
+}
+
+impl Condition {
+    fn matches<B>(&self, req: &Request<B>, remote_addr: IpAddr) -> bool {
+        match self {
+            Self::Path(regex) => regex.is_match(req.uri().path()),
+            Self::Method(methods) => methods.contains(req.method()),
+            Self::Host(regex) => host(req).is_some_and(|host| regex.is_match(&host)),
+            Self::Header(name, regex) => req.headers().get_all(name).iter().any(|value| {
+                regex
+                    .as_ref()
+                    .is_none_or(|regex| value.to_str().is_ok_and(|value| regex.is_match(value)))
+            }),
+            Self::Query(name, regex) => req.uri().query().is_some_and(|query| {
+                form_urlencoded::parse(query.as_bytes()).any(|(k, v)| {
+                    k == name.as_str() && regex.as_ref().is_none_or(|regex| regex.is_match(&v))
+                })
+            }),
+            Self::Source(nets) => acl::contains(nets, remote_addr),
+        }
+    }
+}
+
+// HTTP/2 requests carry the host in the uri
+fn host<B>(req: &Request<B>) -> Option<String> {
+    match req.uri().host() {
+        Some(host) => Some(host.to_owned()),
+        None => req
+            .headers()
+            .get(HOST)
+            .and_then(|host| host.to_str().ok())
+            .and_then(|host| Authority::from_str(host).ok())
+            .map(|authority| authority.host().to_owned()),
+    }
+}
+
+/// Returns the route of the request, or `None` if it's rejected
+///
+/// The first matching rule decides, when none matches the request is allowed only if there are no allow rules
+pub fn route<'a, B>(
+    filters: &'a [Filter],
+    req: &Request<B>,
+    remote_addr: IpAddr,
+) -> Option<Route<'a>> {
+    match filters
+        .iter()
+        .find(|filter| filter.matches(req, remote_addr))
+    {
+        Some(filter) => (filter.action == Action::Allow).then(|| Route {
+            tunnel: filter.tunnel.as_deref().unwrap_or(common::DEFAULT_TUNNEL),
+            timeouts: filter.timeouts,
+        }),
+        None => filters
+            .iter()
+            .all(|filter| filter.action == Action::Deny)
+            .then(|| Route {
+                tunnel: common::DEFAULT_TUNNEL,
+                timeouts: Timeouts::default(),
+            }),
+    }
+}
+
+impl FromStr for Filter {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut tokens = s.split_whitespace().peekable();
+        let action = match tokens.next_if(|token| matches!(*token, "allow" | "deny")) {
+            Some("deny") => Action::Deny,
+            _ => Action::Allow,
+        };
+
+        let mut tunnel = None;
+        let mut timeouts = Timeouts::default();
+        let mut conditions = vec![];
+        for token in tokens {
+            if let Some(name) = token.strip_prefix("tunnel=") {
+                if action == Action::Deny {
+                    return Err(Error::DenyTunnel);
+                }
+                tunnel = Some(name.to_owned());
+            } else if let Some(value) = token.strip_prefix("timeouts=") {
+                if action == Action::Deny {
+                    return Err(Error::DenyTimeouts);
+                }
+                timeouts = Timeouts::from_str(value)?;
+            } else {
+                conditions.push(Condition::from_str(token)?);
+            }
+        }
+        if conditions.is_empty() {
+            return Err(Error::Empty);
+        }
+
+        Ok(Self {
+            action,
+            conditions,
+            tunnel,
+            timeouts,
+        })
+    }
+}
+
+impl FromStr for Condition {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let Some((key, value)) = s.split_once('=') else {
+            return Ok(Self::Path(Regex::new(s)?));
+        };
+
+        match key {
+            "path" => Ok(Self::Path(Regex::new(value)?)),
+            "method" => Ok(Self::Method(
+                value
+                    .split(',')
+                    .map(|method| {
+                        Method::from_str(method).map_err(|_| Error::Method(method.into()))
+                    })
+                    .collect::<Result<_, _>>()?,
+            )),
+            "host" => Ok(Self::Host(Regex::new(value)?)),
+            "header" => {
+                let (name, regex) = split_pattern(value)?;
+                let name =
+                    HeaderName::from_str(name).map_err(|_| Error::HeaderName(name.into()))?;
+                Ok(Self::Header(name, regex))
+            }
+            "query" => {
+                let (name, regex) = split_pattern(value)?;
+                Ok(Self::Query(name.to_owned(), regex))
+            }
+            "source" => Ok(Self::Source(
+                value
+                    .split(',')
+                    .map(|net| acl::parse_net(net).map_err(|_| Error::Source(net.into())))
+                    .collect::<Result<_, _>>()?,
+            )),
+            _ => Err(Error::Key(key.into())),
+        }
+    }
+}
+
+// splits `NAME[:REGEX]`
+fn split_pattern(value: &str) -> Result<(&str, Option<Regex>), Error> {
+    match value.split_once(':') {
+        Some((name, regex)) => Ok((name, Some(Regex::new(regex)?))),
+        None => Ok((value, None)),
+    }
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+    #[error("filter without conditions")]
+    Empty,
+    #[error("deny filters can't have a tunnel")]
+    DenyTunnel,
+    #[error("deny filters can't have timeouts")]
+    DenyTimeouts,
+    #[error("unknown filter condition `{0}`")]
+    Key(String),
+    #[error("invalid method `{0}`")]
+    Method(String),
+    #[error("invalid header name `{0}`")]
+    HeaderName(String),
+    #[error("invalid source `{0}`")]
+    Source(String),
+    #[error(transparent)]
+    Regex(#[from] regex::Error),
+    #[error(transparent)]
+    Timeouts(#[from] common::timeouts::Error),
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+
+    const CALLER: IpAddr = IpAddr::V4(std::net::Ipv4Addr::new(10, 1, 2, 3));
+
+    fn filters(rules: &[&str]) -> Vec<Filter> {
+        rules.iter().map(|rule| rule.parse().unwrap()).collect()
+    }
+
+    fn request(method: &str, uri: &str, headers: &[(&str, &str)]) -> Request<()> {
+        let mut builder = Request::builder().method(method).uri(uri);
+        for (name, value) in headers {
+            builder = builder.header(*name, *value);
+        }
+        builder.body(()).unwrap()
+    }
+
+    fn tunnel(filters: &[Filter], req: &Request<()>) -> Option<String> {
+        route(filters, req, CALLER).map(|route| route.tunnel.to_owned())
+    }
+
+    #[test]
+    fn parse() {
+        let filter = Filter::from_str("deny path=^/admin method=GET,HEAD").unwrap();
+        assert_eq!(filter.action, Action::Deny);
+        assert_eq!(filter.conditions.len(), 2);
+
+        let filter = Filter::from_str("^/api/ tunnel=api timeouts=ttfb=2s").unwrap();
+        assert_eq!(filter.action, Action::Allow);
+        assert!(matches!(&filter.conditions[..], [Condition::Path(_)]));
+        assert_eq!(filter.tunnel.as_deref(), Some("api"));
+        assert_eq!(filter.timeouts.ttfb, Some(Duration::from_secs(2)));
+
+        let filter = Filter::from_str(
+            "allow host=^example header=x-key header=x-env:^prod$ query=debug source=10.0.0.0/8,::1",
+        )
+        .unwrap();
+        assert_eq!(filter.conditions.len(), 5);
+    }
+
+    #[test]
+    fn parse_errors() {
+        assert!(matches!(Filter::from_str(""), Err(Error::Empty)));
+        assert!(matches!(Filter::from_str("allow"), Err(Error::Empty)));
+        assert!(matches!(Filter::from_str("tunnel=api"), Err(Error::Empty)));
+        assert!(matches!(
+            Filter::from_str("deny path=^/ tunnel=api"),
+            Err(Error::DenyTunnel)
+        ));
+        assert!(matches!(
+            Filter::from_str("deny path=^/ timeouts=ttfb=1s"),
+            Err(Error::DenyTimeouts)
+        ));
+        assert!(matches!(
+            Filter::from_str("path=^/ timeouts=soon"),
+            Err(Error::Timeouts(_))
+        ));
+        assert!(matches!(Filter::from_str("port=80"), Err(Error::Key(_))));
+        assert!(matches!(
+            Filter::from_str("method=GE(T"),
+            Err(Error::Method(_))
+        ));
+        assert!(matches!(
+            Filter::from_str("header=x:(unclosed"),
+            Err(Error::Regex(_))
+        ));
+        assert!(matches!(
+            Filter::from_str("header=x\"y"),
+            Err(Error::HeaderName(_))
+        ));
+        assert!(matches!(
+            Filter::from_str("source=10.0.0.0/33"),
+            Err(Error::Source(_))
+        ));
+        assert!(matches!(
+            Filter::from_str("path=(unclosed"),
+            Err(Error::Regex(_))
+        ));
+    }
+
+    #[test]
+    fn conditions() {
+        let filters = filters(&[
+            "method=POST path=^/upload host=^files\\. tunnel=files",
+            "header=x-env:^prod$ tunnel=prod",
+            "query=debug:^1$ tunnel=debug",
+            "source=192.168.0.0/16 tunnel=lan",
+            "source=10.0.0.0/8",
+        ]);
+        let req = request("POST", "/upload", &[("host", "files.example.com:8080")]);
+        assert_eq!(tunnel(&filters, &req).as_deref(), Some("files"));
+        // every condition must match
+        let req = request("GET", "/upload", &[("host", "files.example.com")]);
+        assert_eq!(
+            tunnel(&filters, &req).as_deref(),
+            Some(common::DEFAULT_TUNNEL)
+        );
+        // HTTP/2 requests carry the host in the uri
+        let req = request("POST", "https://files.example.com/upload", &[]);
+        assert_eq!(tunnel(&filters, &req).as_deref(), Some("files"));
+
+        let req = request("GET", "/", &[("x-env", "staging"), ("x-env", "prod")]);
+        assert_eq!(tunnel(&filters, &req).as_deref(), Some("prod"));
+        let req = request("GET", "/?a=b&debug=1", &[]);
+        assert_eq!(tunnel(&filters, &req).as_deref(), Some("debug"));
+        let req = request("GET", "/?debug=2", &[]);
+        assert_eq!(
+            tunnel(&filters, &req).as_deref(),
+            Some(common::DEFAULT_TUNNEL)
+        );
+
+        let lan = "192.168.1.1".parse().unwrap();
+        assert_eq!(
+            route(&filters, &req, lan).map(|route| route.tunnel),
+            Some("lan")
+        );
+        let outside = "172.16.0.1".parse().unwrap();
+        assert!(route(&filters, &req, outside).is_none());
+    }
+
+    #[test]
+    fn first_match_wins() {
+        let filters = filters(&[
+            "deny path=^/admin",
+            "path=^/admin/public tunnel=public",
+            "path=^/api/ tunnel=api",
+            "path=^/api/v2 tunnel=v2",
+        ]);
+        assert_eq!(
+            tunnel(&filters, &request("GET", "/admin/public", &[])),
+            None
+        );
+        assert_eq!(
+            tunnel(&filters, &request("GET", "/api/v2/x", &[])).as_deref(),
+            Some("api")
+        );
+        // with allow rules, requests matching none are rejected
+        assert_eq!(tunnel(&filters, &request("GET", "/other", &[])), None);
+    }
+
+    #[test]
+    fn deny_only() {
+        let filters = filters(&["deny path=^/admin", "deny source=192.168.0.0/16"]);
+        assert_eq!(tunnel(&filters, &request("GET", "/admin", &[])), None);
+        assert_eq!(
+            tunnel(&filters, &request("GET", "/other", &[])).as_deref(),
+            Some(common::DEFAULT_TUNNEL)
+        );
+        assert_eq!(
+            tunnel(&[], &request("GET", "/", &[])).as_deref(),
+            Some(common::DEFAULT_TUNNEL)
+        );
+    }
+
+    #[test]
+    fn route_timeouts() {
+        let filters = filters(&["path=^/export timeouts=total=5m", "path=^/"]);
+        let export = route(&filters, &request("GET", "/export", &[]), CALLER).unwrap();
+        assert_eq!(export.timeouts.total, Some(Duration::from_secs(300)));
+        let other = route(&filters, &request("GET", "/", &[]), CALLER).unwrap();
+        assert_eq!(other.timeouts, Timeouts::default());
+    }
+}
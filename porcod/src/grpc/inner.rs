@@ -1,87 +1,733 @@
 use std::{
     collections::HashMap,
-    pin::Pin,
-    sync::Arc,
-    task::{ready, Context, Poll},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    time::Duration,
 };
 
-use tokio::sync::{
-    broadcast::{self, Receiver, Sender},
-    mpsc, oneshot, Mutex, MutexGuard,
+use time::OffsetDateTime;
+use tokio::{
+    sync::{mpsc, oneshot, watch, Mutex, MutexGuard, Notify, OwnedSemaphorePermit, Semaphore},
+    time::{sleep_until, Instant},
 };
-use tokio_stream::{wrappers::BroadcastStream, Stream};
-use tonic::{Request, Response, Status};
-use tracing::error;
+use tokio_stream::wrappers::ReceiverStream;
+use tonic::{metadata::MetadataValue, Request, Response, Status};
+use tracing::{debug, field, info_span, Instrument, Span};
+
+use common::{
+    problem::{Kind, Problem},
+    telemetry,
+};
+
+use crate::{health::Health, metrics, Reply, Served};
 
 tonic::include_proto!("inner");
 
+// requests a session holds besides the ones porcoc is serving, the rest stays available to other sessions
+const SESSION_BUFFER: usize = 1;
+
+/// The tunnels, served to porcoc over gRPC
 #[derive(Debug)]
 pub struct Inner {
     id_manager: IdManager,
-    broacast_tx: Sender<common::grpc::IncomingRequest>,
+    tunnels: Tunnels,
+    sessions: Sessions,
+    health: Arc<Health>,
 }
 
 impl Inner {
-    pub fn new(mut request_tx: mpsc::Receiver<crate::ChannelItem>) -> Self {
+    pub fn new(
+        mut request_tx: mpsc::Receiver<crate::ChannelItem>,
+        queue_depth: usize,
+        max_in_flight: Option<usize>,
+        health: Arc<Health>,
+    ) -> Self {
         let id_manager = IdManager::default();
+        let sessions = Sessions::default();
+        let tunnels = Tunnels {
+            queue_depth,
+            max_in_flight,
+            tunnels: Arc::default(),
+        };
 
-        // convert `mpsc` into `broadcast`
-        let (broacast_tx, _broadcast_rx) = broadcast::channel(1);
+        // move requests into the queue of their tunnel
         tokio::spawn({
             let id_manager = id_manager.clone();
-            let broacast_tx = broacast_tx.clone();
+            let tunnels = tunnels.clone();
+            let sessions = sessions.clone();
             async move {
-                while let Some((request, oneshot_tx)) = request_tx.recv().await {
-                    let mut id_manager = id_manager.lock().await;
-                    let id = id_manager.inc_id();
-                    id_manager.receivers.insert(id, oneshot_tx);
-                    if let Err(err) =
-                        broacast_tx.send(common::grpc::IncomingRequest::from((id, request)))
-                    {
-                        error!("{err}");
+                while let Some((request, name, deadline, oneshot_tx)) = request_tx.recv().await {
+                    let tunnel = tunnels.get(name.clone()).await;
+                    if tunnel.draining.load(Ordering::Relaxed) {
+                        debug!(
+                            "Tunnel {name} draining, rejecting request {}",
+                            request.request_id
+                        );
+                        metrics::REJECTIONS.with_label_values(&["draining"]).inc();
+                        let _ = oneshot_tx.send(Reply::from(
+                            Problem::new(Kind::Unavailable)
+                                .with_detail(format!("Tunnel {name} draining")),
+                        ));
+                        continue;
                     }
+                    // without a healthy session nobody picks up requests, better to tell the caller now than on timeout
+                    match sessions.healthy(&name) {
+                        None => {
+                            debug!(
+                                "Tunnel {name} has no session, rejecting request {}",
+                                request.request_id
+                            );
+                            metrics::REJECTIONS.with_label_values(&["no_tunnel"]).inc();
+                            let _ = oneshot_tx.send(Reply::from(
+                                Problem::new(Kind::NoTunnel)
+                                    .with_detail(format!("No porcoc serves tunnel {name}")),
+                            ));
+                            continue;
+                        }
+                        Some(false) => {
+                            debug!(
+                                "Tunnel {name} targets unhealthy, rejecting request {}",
+                                request.request_id
+                            );
+                            metrics::REJECTIONS.with_label_values(&["unhealthy"]).inc();
+                            let _ = oneshot_tx.send(Reply::from(
+                                Problem::new(Kind::Unavailable)
+                                    .with_detail(format!("Tunnel {name} targets unhealthy")),
+                            ));
+                            continue;
+                        }
+                        Some(true) => {}
+                    }
+                    let in_flight = match tunnel.in_flight.map(Semaphore::try_acquire_owned) {
+                        None => None,
+                        Some(Ok(permit)) => Some(permit),
+                        Some(Err(_)) => {
+                            debug!(
+                                "Tunnel {name} overloaded, rejecting request {}",
+                                request.request_id
+                            );
+                            metrics::REJECTIONS.with_label_values(&["overload"]).inc();
+                            let _ = oneshot_tx
+                                .send(Reply::from(Problem::new(Kind::Overloaded).with_detail(
+                                    format!("Tunnel {name} in-flight limit reached"),
+                                )));
+                            continue;
+                        }
+                    };
+                    let Ok(queued) = tunnel.queue_tx.try_reserve() else {
+                        debug!(
+                            "Tunnel {name} queue full, rejecting request {}",
+                            request.request_id
+                        );
+                        metrics::REJECTIONS.with_label_values(&["overload"]).inc();
+                        let _ = oneshot_tx.send(Reply::from(
+                            Problem::new(Kind::Overloaded)
+                                .with_detail(format!("Tunnel {name} queue full")),
+                        ));
+                        continue;
+                    };
+
+                    let (response_tx, response_rx) = oneshot::channel();
+                    let request_id = request.request_id.clone();
+                    let id = {
+                        let mut id_manager = id_manager.lock().await;
+                        let id = id_manager.inc_id();
+                        id_manager.insert(
+                            id,
+                            Pending {
+                                deadline,
+                                since: Instant::now(),
+                                response_tx,
+                                session: None,
+                                request_id: request_id.clone(),
+                                tunnel: name.clone(),
+                                method: request.method.to_string(),
+                                uri: request.uri.to_string(),
+                            },
+                        );
+                        debug!(
+                            "Queueing request {id} ({request_id}) on tunnel {name}: {} waiting, {} queued on the tunnel, {} pending",
+                            request_tx.len(),
+                            queue_len(&tunnel.queue_tx),
+                            id_manager.receivers.len(),
+                        );
+                        id
+                    };
+                    queued.send(common::grpc::IncomingRequest::from((id, request)));
+                    metrics::QUEUED
+                        .with_label_values(&[&name])
+                        .set(queue_len(&tunnel.queue_tx));
+                    tokio::spawn(
+                        reap(
+                            id,
+                            name,
+                            deadline,
+                            oneshot_tx,
+                            response_rx,
+                            id_manager.clone(),
+                            in_flight,
+                        )
+                        .instrument(info_span!("reap", id, request_id)),
+                    );
                 }
             }
         });
 
         Self {
             id_manager,
-            broacast_tx,
+            tunnels,
+            sessions,
+            health,
+        }
+    }
+
+    /// A handle to inspect and manage the tunnels while they're served
+    pub fn admin(&self) -> Admin {
+        Admin {
+            id_manager: self.id_manager.clone(),
+            tunnels: self.tunnels.clone(),
+            sessions: self.sessions.clone(),
+        }
+    }
+}
+
+/// Relays the response of request `id` to its caller, or drops the pending entry and notifies the session serving it
+/// when the caller goes away or the deadline expires
+async fn reap(
+    id: u64,
+    tunnel: String,
+    deadline: Instant,
+    mut oneshot_tx: oneshot::Sender<Reply>,
+    response_rx: oneshot::Receiver<Reply>,
+    id_manager: IdManager,
+    // keeps the request counted against the tunnel in-flight limit
+    _in_flight: Option<OwnedSemaphorePermit>,
+) {
+    let start = Instant::now();
+    tokio::select! {
+        res = response_rx => {
+            // an error here means the entry has already been removed
+            if let Ok(response) = res {
+                metrics::TUNNEL_DURATION
+                    .with_label_values(&[&tunnel])
+                    .observe(start.elapsed().as_secs_f64());
+                if oneshot_tx.send(response).is_err() {
+                    debug!("Caller of request {id} went away before the response");
+                }
+            }
+            return;
+        }
+        _ = oneshot_tx.closed() => debug!("Caller of request {id} went away"),
+        _ = sleep_until(deadline) => {
+            debug!("Request {id} expired");
+            let _ = oneshot_tx.send(Reply::from(
+                Problem::new(Kind::Timeout).with_detail("No response from porcoc in time"),
+            ));
+        }
+    }
+
+    // requests still queued are skipped by sessions once their entry is gone
+    let pending = id_manager.lock().await.remove(id);
+    if let Some(session) = pending.and_then(|pending| pending.session) {
+        // a closed session has nothing to cancel
+        let _ = session.cancel_tx.send(id);
+    }
+}
+
+/// Feeds a porcoc session with requests from the tunnel queue, competing with the other sessions of the same tunnel,
+/// and with cancels of the requests it picked up
+async fn serve_session(
+    session: Registration,
+    tunnel: Tunnel,
+    id_manager: IdManager,
+    session_tx: mpsc::Sender<Result<common::grpc::TunnelMessage, Status>>,
+    health: Arc<Health>,
+) {
+    let Registration {
+        id: session_id,
+        name,
+        tunnel: tunnel_name,
+        disconnect,
+        mut healthy,
+        sessions: registry,
+    } = session;
+    let (cancel_tx, mut cancel_rx) = mpsc::unbounded_channel();
+    let mut paused = tunnel.paused.subscribe();
+    let sessions = metrics::SESSIONS.with_label_values(&[&tunnel_name]);
+    sessions.inc();
+    health.session_opened(&tunnel_name);
+    loop {
+        tokio::select! {
+            biased;
+            _ = session_tx.closed() => break,
+            _ = disconnect.notified() => {
+                debug!("Disconnecting session {name}");
+                break;
+            }
+            Some(id) = cancel_rx.recv() => {
+                let cancel = common::grpc::TunnelMessage::from(common::grpc::Cancel { id });
+                if session_tx.send(Ok(cancel)).await.is_err() {
+                    break;
+                }
+            }
+            // re-evaluate whether the session can pick up requests
+            Ok(()) = paused.changed() => {}
+            Ok(()) = healthy.changed() => {}
+            // both steps are cancel safe, so a request is never lost when a cancel wins the race
+            next = async {
+                let permit = session_tx.reserve().await.ok()?;
+                let mut queue_rx = tunnel.queue_rx.lock().await;
+                let request = queue_rx.recv().await?;
+                metrics::QUEUED.with_label_values(&[&tunnel_name]).set(queue_rx.len() as i64);
+                Some((permit, request))
+            }, if !*paused.borrow() && *healthy.borrow() => {
+                let Some((permit, mut request)) = next else {
+                    break;
+                };
+                match id_manager.lock().await.receivers.get_mut(&request.id) {
+                    Some(pending) => {
+                        pending.session = Some(Session {
+                            id: session_id,
+                            name: name.clone(),
+                            cancel_tx: cancel_tx.clone(),
+                        });
+                        // porcoc gets the time actually left, after waiting in the queue
+                        if let Some(timeouts) = request.timeouts.as_mut() {
+                            let left = pending.deadline.saturating_duration_since(Instant::now());
+                            timeouts.total = (left.as_millis() as u64).max(1);
+                        }
+                    }
+                    None => {
+                        debug!(
+                            "Skipping request {} ({}), no longer pending",
+                            request.id, request.request_id
+                        );
+                        continue;
+                    }
+                }
+                permit.send(Ok(common::grpc::TunnelMessage::from(request)));
+            }
+        }
+    }
+
+    sessions.dec();
+    health.session_closed(&tunnel_name);
+    registry.remove(session_id);
+
+    // requests picked up by this session will never be answered, better to fail them now than on timeout
+    let mut id_manager = id_manager.lock().await;
+    let orphans = id_manager
+        .receivers
+        .iter()
+        .filter(|(_, pending)| {
+            pending
+                .session
+                .as_ref()
+                .is_some_and(|session| session.id == session_id)
+        })
+        .map(|(id, _)| *id)
+        .collect::<Vec<_>>();
+    for id in orphans {
+        if let Some(pending) = id_manager.remove(id) {
+            debug!(
+                "Session closed while serving request {id} ({})",
+                pending.request_id
+            );
+            let _ = pending.response_tx.send(Reply {
+                response: Err(Problem::new(Kind::Unavailable)
+                    .with_detail("porcoc session closed while serving the request")),
+                served: Served {
+                    session: Some(name.clone()),
+                },
+            });
         }
     }
 }
 
 #[tonic::async_trait]
 impl inner_server::Inner for Inner {
-    type StreamRequestsStream = StreamRequestsStream;
+    type StreamRequestsStream = ReceiverStream<Result<common::grpc::TunnelMessage, Status>>;
 
     async fn stream_requests(
         &self,
-        _: Request<common::grpc::Void>,
+        request: Request<common::grpc::Subscription>,
     ) -> Result<Response<Self::StreamRequestsStream>, Status> {
-        Ok(Response::new(StreamRequestsStream::new(
+        // porcoc is known by its address, the port tells apart sessions from the same host
+        let name = request
+            .remote_addr()
+            .map_or_else(|| Arc::from("unknown"), |addr| Arc::from(addr.to_string()));
+        let common::grpc::Subscription {
+            mut tunnel,
+            identity,
+            version,
+        } = request.into_inner();
+        if tunnel.is_empty() {
+            tunnel = common::DEFAULT_TUNNEL.to_owned();
+        }
+        debug!("New session {name} for tunnel {tunnel}");
+        let session = self.sessions.register(SessionInfo {
+            id: 0,
+            tunnel: tunnel.clone(),
+            address: name,
+            identity,
+            version,
+            connected_since: OffsetDateTime::now_utc(),
+            in_flight: 0,
+            healthy: true,
+        });
+        let id = session.id;
+        let (session_tx, session_rx) = mpsc::channel(SESSION_BUFFER);
+        tokio::spawn(serve_session(
+            session,
+            self.tunnels.get(tunnel).await,
             self.id_manager.clone(),
-            self.broacast_tx.subscribe(),
-        )))
+            session_tx,
+            self.health.clone(),
+        ));
+        let mut response = Response::new(ReceiverStream::new(session_rx));
+        response
+            .metadata_mut()
+            .insert(common::SESSION_METADATA, MetadataValue::from(id));
+        Ok(response)
+    }
+
+    async fn report_health(
+        &self,
+        request: Request<common::grpc::TargetHealth>,
+    ) -> Result<Response<common::grpc::Void>, Status> {
+        let common::grpc::TargetHealth { session, healthy } = request.into_inner();
+        debug!("Session {session} reports target healthy: {healthy}");
+        if !self.sessions.set_healthy(session, healthy) {
+            return Err(Status::not_found("Unknown session"));
+        }
+        Ok(Response::new(common::grpc::Void {}))
     }
 
     async fn send_response(
         &self,
         request: Request<common::grpc::OutgoingResponse>,
     ) -> Result<Response<common::grpc::Void>, Status> {
-        let response = request.into_inner();
-        let oneshot_tx = {
-            let mut id_manager = self.id_manager.lock().await;
-            id_manager
-                .receivers
-                .remove(&response.id)
-                .ok_or_else(|| Status::invalid_argument("Invalid request id"))?
+        let span = info_span!(
+            "send_response",
+            id = request.get_ref().id,
+            request_id = field::Empty
+        );
+        // porcoc propagates the trace of the request it served
+        telemetry::set_parent_from_metadata(&span, request.metadata());
+        async move {
+            let response = request.into_inner();
+            let pending = {
+                let mut id_manager = self.id_manager.lock().await;
+                id_manager
+                    .remove(response.id)
+                    .ok_or_else(|| Status::invalid_argument("Invalid request id"))?
+            };
+            Span::current().record("request_id", &pending.request_id);
+            if pending.deadline <= Instant::now() {
+                return Err(Status::deadline_exceeded("Timed out"));
+            }
+            let reply = Reply {
+                served: Served {
+                    session: pending.session.map(|session| session.name),
+                },
+                response: Ok(common::OutgoingResponse::try_from(response)?),
+            };
+            if pending.response_tx.send(reply).is_err() {
+                return Err(Status::deadline_exceeded("Timed out"));
+            }
+            Ok(Response::new(common::grpc::Void {}))
+        }
+        .instrument(span)
+        .await
+    }
+}
+
+/// Inspects and manages the tunnels on behalf of operators
+#[derive(Debug, Clone)]
+pub struct Admin {
+    id_manager: IdManager,
+    tunnels: Tunnels,
+    sessions: Sessions,
+}
+
+/// A connected porcoc session
+#[derive(Debug, Clone)]
+pub struct SessionInfo {
+    pub id: u64,
+    pub tunnel: String,
+    /// porcoc address, as seen by porcod
+    pub address: Arc<str>,
+    /// as declared by porcoc, may be empty
+    pub identity: String,
+    pub version: String,
+    pub connected_since: OffsetDateTime,
+    /// requests picked up and not answered yet
+    pub in_flight: usize,
+    /// as reported by porcoc, unhealthy sessions don't pick up requests
+    pub healthy: bool,
+}
+
+/// A request waiting for its response
+#[derive(Debug, Clone)]
+pub struct PendingInfo {
+    pub id: u64,
+    pub request_id: String,
+    pub tunnel: String,
+    pub method: String,
+    pub uri: String,
+    /// session serving the request, `None` while queued
+    pub session: Option<u64>,
+    pub age: Duration,
+    pub expires_in: Duration,
+}
+
+/// A tunnel, with its state
+#[derive(Debug, Clone)]
+pub struct TunnelInfo {
+    pub name: String,
+    pub sessions: usize,
+    pub queued: usize,
+    pub paused: bool,
+    pub draining: bool,
+}
+
+impl Admin {
+    pub async fn sessions(&self) -> Vec<SessionInfo> {
+        let mut sessions = self.sessions.list();
+        let id_manager = self.id_manager.lock().await;
+        for pending in id_manager.receivers.values() {
+            if let Some(session) = &pending.session {
+                if let Some(info) = sessions.iter_mut().find(|info| info.id == session.id) {
+                    info.in_flight += 1;
+                }
+            }
+        }
+        sessions
+    }
+
+    pub async fn pending(&self) -> Vec<PendingInfo> {
+        let now = Instant::now();
+        let mut pending = self
+            .id_manager
+            .lock()
+            .await
+            .receivers
+            .iter()
+            .map(|(id, pending)| PendingInfo {
+                id: *id,
+                request_id: pending.request_id.clone(),
+                tunnel: pending.tunnel.clone(),
+                method: pending.method.clone(),
+                uri: pending.uri.clone(),
+                session: pending.session.as_ref().map(|session| session.id),
+                age: now.saturating_duration_since(pending.since),
+                expires_in: pending.deadline.saturating_duration_since(now),
+            })
+            .collect::<Vec<_>>();
+        pending.sort_by_key(|pending| pending.id);
+        pending
+    }
+
+    pub async fn tunnels(&self) -> Vec<TunnelInfo> {
+        let sessions = self.sessions.list();
+        let mut tunnels = self
+            .tunnels
+            .tunnels
+            .lock()
+            .await
+            .iter()
+            .map(|(name, tunnel)| TunnelInfo {
+                name: name.clone(),
+                sessions: sessions
+                    .iter()
+                    .filter(|session| &session.tunnel == name)
+                    .count(),
+                queued: queue_len(&tunnel.queue_tx) as usize,
+                paused: *tunnel.paused.borrow(),
+                draining: tunnel.draining.load(Ordering::Relaxed),
+            })
+            .collect::<Vec<_>>();
+        tunnels.sort_by(|a, b| a.name.cmp(&b.name));
+        tunnels
+    }
+
+    /// Closes a session, its in-flight requests are answered with 503; porcoc is free to reconnect
+    pub fn disconnect(&self, session: u64) -> bool {
+        self.sessions.disconnect(session)
+    }
+
+    /// Stops handing requests to porcoc, they stay queued until resumed or expired
+    pub async fn pause(&self, tunnel: &str) {
+        debug!("Pausing tunnel {tunnel}");
+        self.tunnels
+            .get(tunnel.to_owned())
+            .await
+            .paused
+            .send_replace(true);
+    }
+
+    /// Answers new requests with 503, while the queued and in-flight ones complete
+    pub async fn drain(&self, tunnel: &str) {
+        debug!("Draining tunnel {tunnel}");
+        let tunnel = self.tunnels.get(tunnel.to_owned()).await;
+        tunnel.draining.store(true, Ordering::Relaxed);
+        tunnel.paused.send_replace(false);
+    }
+
+    /// Undoes pause and drain
+    pub async fn resume(&self, tunnel: &str) {
+        debug!("Resuming tunnel {tunnel}");
+        let tunnel = self.tunnels.get(tunnel.to_owned()).await;
+        tunnel.draining.store(false, Ordering::Relaxed);
+        tunnel.paused.send_replace(false);
+    }
+}
+
+/// Tunnels by name, created on first use
+#[derive(Debug, Clone)]
+struct Tunnels {
+    queue_depth: usize,
+    max_in_flight: Option<usize>,
+    tunnels: Arc<Mutex<HashMap<String, Tunnel>>>,
+}
+
+/// Requests waiting for a porcoc session of the tunnel to pick them up
+#[derive(Debug, Clone)]
+struct Tunnel {
+    queue_tx: mpsc::Sender<common::grpc::IncomingRequest>,
+    queue_rx: Arc<Mutex<mpsc::Receiver<common::grpc::IncomingRequest>>>,
+    in_flight: Option<Arc<Semaphore>>,
+    /// sessions don't pick up requests while paused
+    paused: Arc<watch::Sender<bool>>,
+    /// new requests are rejected while draining
+    draining: Arc<AtomicBool>,
+}
+
+impl Tunnels {
+    async fn get(&self, tunnel: String) -> Tunnel {
+        self.tunnels
+            .lock()
+            .await
+            .entry(tunnel)
+            .or_insert_with(|| {
+                let (queue_tx, queue_rx) = mpsc::channel(self.queue_depth);
+                Tunnel {
+                    queue_tx,
+                    queue_rx: Arc::new(Mutex::new(queue_rx)),
+                    in_flight: self
+                        .max_in_flight
+                        .map(|permits| Arc::new(Semaphore::new(permits))),
+                    paused: Arc::new(watch::Sender::new(false)),
+                    draining: Arc::default(),
+                }
+            })
+            .clone()
+    }
+}
+
+/// Connected porcoc sessions, by id
+#[derive(Debug, Default, Clone)]
+struct Sessions(Arc<std::sync::Mutex<SessionsInner>>);
+
+#[derive(Debug, Default)]
+struct SessionsInner {
+    next_id: u64,
+    sessions: HashMap<u64, Entry>,
+}
+
+#[derive(Debug)]
+struct Entry {
+    info: SessionInfo,
+    disconnect: Arc<Notify>,
+    healthy: watch::Sender<bool>,
+}
+
+/// A session as known to the registry, until it's removed
+#[derive(Debug)]
+struct Registration {
+    id: u64,
+    name: Arc<str>,
+    tunnel: String,
+    disconnect: Arc<Notify>,
+    healthy: watch::Receiver<bool>,
+    sessions: Sessions,
+}
+
+impl Sessions {
+    fn register(&self, mut info: SessionInfo) -> Registration {
+        let mut inner = self.0.lock().unwrap();
+        inner.next_id += 1;
+        info.id = inner.next_id;
+        let (healthy_tx, healthy_rx) = watch::channel(info.healthy);
+        let registration = Registration {
+            id: info.id,
+            name: info.address.clone(),
+            tunnel: info.tunnel.clone(),
+            disconnect: Arc::default(),
+            healthy: healthy_rx,
+            sessions: self.clone(),
         };
-        let response = common::OutgoingResponse::try_from(response)?;
-        if oneshot_tx.send(response).is_err() {
-            return Err(Status::deadline_exceeded("Timed out"));
+        inner.sessions.insert(
+            info.id,
+            Entry {
+                info,
+                disconnect: registration.disconnect.clone(),
+                healthy: healthy_tx,
+            },
+        );
+        registration
+    }
+
+    fn remove(&self, id: u64) {
+        self.0.lock().unwrap().sessions.remove(&id);
+    }
+
+    fn list(&self) -> Vec<SessionInfo> {
+        let mut sessions = self
+            .0
+            .lock()
+            .unwrap()
+            .sessions
+            .values()
+            .map(|entry| entry.info.clone())
+            .collect::<Vec<_>>();
+        sessions.sort_by_key(|session| session.id);
+        sessions
+    }
+
+    fn disconnect(&self, id: u64) -> bool {
+        match self.0.lock().unwrap().sessions.get(&id) {
+            Some(entry) => {
+                // stored if the session isn't waiting, so it's never missed
+                entry.disconnect.notify_one();
+                true
+            }
+            None => false,
+        }
+    }
+
+    fn set_healthy(&self, id: u64, healthy: bool) -> bool {
+        match self.0.lock().unwrap().sessions.get_mut(&id) {
+            Some(entry) => {
+                entry.info.healthy = healthy;
+                entry.healthy.send_replace(healthy);
+                true
+            }
+            None => false,
         }
-        Ok(Response::new(common::grpc::Void {}))
+    }
+
+    /// Whether any session of the tunnel is healthy, `None` if the tunnel has no sessions
+    fn healthy(&self, tunnel: &str) -> Option<bool> {
+        let inner = self.0.lock().unwrap();
+        inner
+            .sessions
+            .values()
+            .filter(|entry| entry.info.tunnel == tunnel)
+            .map(|entry| entry.info.healthy)
+            .reduce(|a, b| a || b)
     }
 }
 
@@ -97,7 +743,31 @@ impl IdManager {
 #[derive(Debug)]
 struct IdManagerInner {
     next_id: u64,
-    receivers: HashMap<u64, oneshot::Sender<common::OutgoingResponse>>,
+    receivers: HashMap<u64, Pending>,
+}
+
+/// A request waiting for its response from porcoc
+#[derive(Debug)]
+struct Pending {
+    deadline: Instant,
+    /// when it's been queued
+    since: Instant,
+    response_tx: oneshot::Sender<Reply>,
+    /// session serving the request, once picked up
+    session: Option<Session>,
+    /// see [`common::headers::request_id`]
+    request_id: String,
+    tunnel: String,
+    method: String,
+    uri: String,
+}
+
+/// A porcoc session, as seen by the requests it serves
+#[derive(Debug)]
+struct Session {
+    id: u64,
+    name: Arc<str>,
+    cancel_tx: mpsc::UnboundedSender<u64>,
 }
 
 impl Default for IdManagerInner {
@@ -111,40 +781,24 @@ impl Default for IdManagerInner {
 }
 
 impl IdManagerInner {
-    fn inc_id(&mut self) -> u64 {
-        let id = self.next_id;
-        self.next_id += 1;
-        id
+    fn insert(&mut self, id: u64, pending: Pending) {
+        self.receivers.insert(id, pending);
+        metrics::PENDING.set(self.receivers.len() as i64);
     }
-}
 
-pin_project_lite::pin_project! {
-    pub struct StreamRequestsStream {
-        id_manager: IdManager,
-        #[pin]
-        stream: BroadcastStream<common::grpc::IncomingRequest>,
+    fn remove(&mut self, id: u64) -> Option<Pending> {
+        let pending = self.receivers.remove(&id);
+        metrics::PENDING.set(self.receivers.len() as i64);
+        pending
     }
-}
 
-impl StreamRequestsStream {
-    fn new(id_manager: IdManager, request_rx: Receiver<common::grpc::IncomingRequest>) -> Self {
-        Self {
-            id_manager,
-            stream: BroadcastStream::new(request_rx),
-        }
+    fn inc_id(&mut self) -> u64 {
+        let id = self.next_id;
+        self.next_id += 1;
+        id
     }
 }
 
-impl Stream for StreamRequestsStream {
-    type Item = Result<common::grpc::IncomingRequest, Status>;
-
-    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
-        let this = self.project();
-        match ready!(this.stream.poll_next(cx)) {
-            None => Poll::Ready(None),
-            Some(res) => Poll::Ready(Some(
-                res.map_err(|err| Status::resource_exhausted(err.to_string())),
-            )),
-        }
-    }
+fn queue_len<T>(queue_tx: &mpsc::Sender<T>) -> i64 {
+    (queue_tx.max_capacity() - queue_tx.capacity()) as i64
 }
@@ -5,27 +5,45 @@ use hyper_util::{
     rt::{TokioExecutor, TokioIo},
     service::TowerToHyperService,
 };
+use ipnet::IpNet;
 use rustls::{
     pki_types::{CertificateDer, PrivateKeyDer},
     ServerConfig,
 };
-use tokio::{net::TcpListener, sync::mpsc::Receiver};
+use tokio::net::TcpListener;
 use tokio_rustls::TlsAcceptor;
-use tonic::{body::boxed, service::Routes};
+use tonic::{body::boxed, service::Routes, transport::server::TcpConnectInfo};
 use tower::ServiceExt;
 use tracing::{debug, error};
 
-use crate::tls::Tls;
+use crate::{acl::Acl, health::Health, metrics, proxy_protocol, tls::Tls};
 
 mod inner;
 
+pub use inner::{Admin, Inner, PendingInfo, SessionInfo, TunnelInfo};
+
+/// gRPC listener behavior
+#[derive(Debug)]
+pub struct Config {
+    pub acl: Acl,
+    /// expect a PROXY protocol header from trusted proxies, or from every peer if there are none
+    pub proxy_protocol: bool,
+    pub trusted_proxies: Vec<IpNet>,
+    pub health: Arc<Health>,
+}
+
 pub async fn run(
     addr: SocketAddr,
     cert: Option<(Vec<CertificateDer<'static>>, PrivateKeyDer<'static>)>,
-    request_rx: Receiver<crate::ChannelItem>,
+    config: Config,
+    inner: Inner,
 ) -> anyhow::Result<()> {
-    let inner = inner::Inner::new(request_rx);
-    let svc = Routes::new(inner::inner_server::InnerServer::new(inner));
+    let (mut health_reporter, health_service) = tonic_health::server::health_reporter();
+    health_reporter
+        .set_serving::<inner::inner_server::InnerServer<Inner>>()
+        .await;
+    let inner = inner::inner_server::InnerServer::new(inner);
+    let svc = Routes::new(inner).add_service(health_service);
     let http = Builder::new(TokioExecutor::new());
     let listener = TcpListener::bind(addr).await?;
 
@@ -42,20 +60,44 @@ pub async fn run(
         .transpose()?;
 
     debug!("gRPC listening on http://{}", addr);
+    config.health.grpc_bound();
 
+    let config = Arc::new(config);
     loop {
-        let (stream, _) = listener.accept().await?;
-
+        let (mut stream, mut remote_addr) = listener.accept().await?;
         tokio::spawn({
             let http = http.clone();
             let tls_acceptor = tls_acceptor.clone();
             let svc = svc.clone();
+            let config = config.clone();
             async move {
+                if config.proxy_protocol {
+                    match proxy_protocol::accept(&mut stream, remote_addr, &config.trusted_proxies)
+                        .await
+                    {
+                        Ok(source) => remote_addr = source,
+                        Err(err) => {
+                            error!("Failed to read PROXY header from {remote_addr}: {err}");
+                            return;
+                        }
+                    }
+                }
+
+                if !config.acl.allows(remote_addr.ip()) {
+                    debug!("Rejected connection from {remote_addr}");
+                    metrics::REJECTIONS.with_label_values(&["grpc_acl"]).inc();
+                    return;
+                }
+
+                let local_addr = stream.local_addr().ok();
                 let io = match tls_acceptor {
                     Some(tls_acceptor) => match tls_acceptor.accept(stream).await {
                         Ok(stream) => Tls::Rustls { stream },
                         Err(err) => {
                             error!("failed to perform tls handshake: {err}");
+                            metrics::TLS_HANDSHAKE_FAILURES
+                                .with_label_values(&["grpc"])
+                                .inc();
                             return;
                         }
                     },
@@ -65,9 +107,16 @@ pub async fn run(
                 if let Err(err) = http
                     .serve_connection(
                         TokioIo::new(io),
-                        TowerToHyperService::new(
-                            svc.map_request(|req: http::Request<_>| req.map(boxed)),
-                        ),
+                        TowerToHyperService::new(svc.map_request(
+                            move |mut req: http::Request<_>| {
+                                // exposes the caller, possibly from the PROXY header, as `Request::remote_addr`
+                                req.extensions_mut().insert(TcpConnectInfo {
+                                    local_addr,
+                                    remote_addr: Some(remote_addr),
+                                });
+                                req.map(boxed)
+                            },
+                        )),
                     )
                     .await
                 {
@@ -0,0 +1,74 @@
+use std::{
+    collections::HashMap,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Mutex,
+    },
+};
+
+use common::health::Readiness;
+
+/// porcod readiness, fed by the listeners and the tunnels
+#[derive(Debug, Default)]
+pub struct Health {
+    webserver: AtomicBool,
+    grpc: AtomicBool,
+    /// tunnels needing a porcoc session to be ready
+    required_tunnels: Vec<String>,
+    /// connected porcoc sessions per tunnel
+    sessions: Mutex<HashMap<String, usize>>,
+}
+
+impl Health {
+    pub fn new(required_tunnels: Vec<String>) -> Self {
+        Self {
+            required_tunnels,
+            ..Self::default()
+        }
+    }
+
+    pub fn webserver_bound(&self) {
+        self.webserver.store(true, Ordering::Relaxed);
+    }
+
+    pub fn grpc_bound(&self) {
+        self.grpc.store(true, Ordering::Relaxed);
+    }
+
+    pub fn session_opened(&self, tunnel: &str) {
+        *self
+            .sessions
+            .lock()
+            .unwrap()
+            .entry(tunnel.to_owned())
+            .or_default() += 1;
+    }
+
+    pub fn session_closed(&self, tunnel: &str) {
+        if let Some(count) = self.sessions.lock().unwrap().get_mut(tunnel) {
+            *count = count.saturating_sub(1);
+        }
+    }
+}
+
+impl Readiness for Health {
+    fn ready(&self) -> Result<(), String> {
+        if !self.webserver.load(Ordering::Relaxed) {
+            return Err("webserver not listening".to_owned());
+        }
+        if !self.grpc.load(Ordering::Relaxed) {
+            return Err("gRPC not listening".to_owned());
+        }
+        let sessions = self.sessions.lock().unwrap();
+        let missing = self
+            .required_tunnels
+            .iter()
+            .filter(|tunnel| sessions.get(tunnel.as_str()).copied().unwrap_or_default() == 0)
+            .map(String::as_str)
+            .collect::<Vec<_>>();
+        if !missing.is_empty() {
+            return Err(format!("no porcoc on tunnels {}", missing.join(", ")));
+        }
+        Ok(())
+    }
+}
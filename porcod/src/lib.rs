@@ -1,7 +1,43 @@
-use tokio::sync::oneshot::Sender;
+use std::sync::Arc;
 
-pub type ChannelItem = (common::IncomingRequest, Sender<common::OutgoingResponse>);
+use common::problem::Problem;
+use tokio::{sync::oneshot::Sender, time::Instant};
 
+pub type ChannelItem = (common::IncomingRequest, String, Instant, Sender<Reply>);
+
+/// A response, or why there's none, along with how it has been served
+#[derive(Debug)]
+pub struct Reply {
+    pub response: Result<common::OutgoingResponse, Problem>,
+    pub served: Served,
+}
+
+/// How a request has been served, kept in response extensions
+#[derive(Debug, Clone, Default)]
+pub struct Served {
+    /// porcoc session that answered
+    pub session: Option<Arc<str>>,
+}
+
+impl From<Problem> for Reply {
+    /// An error from porcod itself
+    fn from(problem: Problem) -> Self {
+        Self {
+            response: Err(problem),
+            served: Served::default(),
+        }
+    }
+}
+
+pub mod access_log;
+pub mod acl;
+pub mod admin;
+pub mod error_pages;
+pub mod filter;
 pub mod grpc;
+pub mod health;
+pub mod metrics;
+pub mod proxy_protocol;
+pub mod rate_limit;
 pub mod tls;
 pub mod webserver;
@@ -1,8 +1,26 @@
-use std::{fs, io, net::SocketAddr, path::PathBuf, time::Duration};
+use std::{
+    collections::HashMap, fs, io, net::SocketAddr, path::PathBuf, str::FromStr, sync::Arc,
+    time::Duration,
+};
 
-use clap::Parser;
-use porcod::{grpc, webserver};
-use regex::Regex;
+use clap::{builder::RangedU64ValueParser, Parser};
+use common::{
+    headers::{HeaderPolicy, HeaderRule},
+    timeouts::Timeouts,
+};
+use http::{HeaderName, StatusCode};
+use ipnet::IpNet;
+use porcod::{
+    access_log::{self, AccessLog},
+    acl::{self, Acl},
+    admin,
+    error_pages::{self, ErrorPages},
+    filter::Filter,
+    grpc,
+    health::Health,
+    rate_limit::{Limit, Limiter},
+    webserver,
+};
 use rustls::pki_types::{CertificateDer, PrivateKeyDer};
 use tokio::sync::mpsc::channel;
 
@@ -22,6 +40,66 @@ struct Args {
     #[arg(short = 'K', long)]
     grpc_private_key: Option<PathBuf>,
 
+    /// only accept grpc connections from these networks
+    #[arg(long, value_parser = acl::parse_net)]
+    grpc_allow: Vec<IpNet>,
+
+    /// never accept grpc connections from these networks
+    #[arg(long, value_parser = acl::parse_net)]
+    grpc_deny: Vec<IpNet>,
+
+    /// expect a PROXY protocol (v1 or v2) header on grpc connections
+    #[arg(long)]
+    grpc_proxy_protocol: bool,
+
+    /// proxies sending the PROXY protocol header to grpc, every peer if empty
+    #[arg(long, value_parser = acl::parse_net)]
+    grpc_trusted_proxies: Vec<IpNet>,
+
+    /// requests waiting to be dispatched to their tunnel, further ones are answered with 503
+    #[arg(long, default_value_t = 64, value_parser = RangedU64ValueParser::<usize>::new().range(1..))]
+    queue_depth: usize,
+
+    /// requests buffered per tunnel, waiting for porcoc to pick them up
+    #[arg(long, default_value_t = 64, value_parser = RangedU64ValueParser::<usize>::new().range(1..))]
+    tunnel_queue_depth: usize,
+
+    /// requests a tunnel serves at once, further ones are answered with 503
+    #[arg(long, value_parser = RangedU64ValueParser::<usize>::new().range(1..))]
+    tunnel_max_in_flight: Option<usize>,
+
+    /// tunnels needing a connected porcoc for porcod to be ready
+    #[arg(long)]
+    ready_tunnels: Vec<String>,
+
+    /// access log format, no access log if missing
+    #[arg(long, value_enum)]
+    access_log: Option<access_log::Format>,
+
+    /// access log file, stdout if missing
+    #[arg(long)]
+    access_log_file: Option<PathBuf>,
+
+    /// access log file rotation
+    #[arg(long, value_enum, default_value_t = access_log::Rotate::Daily)]
+    access_log_rotation: access_log::Rotate,
+
+    /// OTLP gRPC endpoint receiving spans (e.g. http://localhost:4317), no export if missing
+    #[arg(long)]
+    otlp_endpoint: Option<String>,
+
+    /// metrics bind address, serving Prometheus metrics on /metrics
+    #[arg(long)]
+    metrics_addr: Option<SocketAddr>,
+
+    /// admin bind address, serving /healthz, /readyz and the admin API under /api/
+    #[arg(long)]
+    admin_addr: Option<SocketAddr>,
+
+    /// file holding the bearer token of the admin API, which is disabled if missing or empty
+    #[arg(long)]
+    admin_token_file: Option<PathBuf>,
+
     /// webserver bind address
     #[arg(short = 'a', long, default_value_t = SocketAddr::from(([0, 0, 0, 0], 80)))]
     webserver_addr: SocketAddr,
@@ -34,39 +112,223 @@ struct Args {
     #[arg(short = 'k', long)]
     webserver_private_key: Option<PathBuf>,
 
-    /// webserver incoming filters
+    /// only accept webserver callers from these networks
+    #[arg(long, value_parser = acl::parse_net)]
+    webserver_allow: Vec<IpNet>,
+
+    /// never accept webserver callers from these networks
+    #[arg(long, value_parser = acl::parse_net)]
+    webserver_deny: Vec<IpNet>,
+
+    /// proxies allowed to forward callers, their scheme and host through X-Forwarded-* and Forwarded
+    #[arg(long, value_parser = acl::parse_net)]
+    webserver_trusted_proxies: Vec<IpNet>,
+
+    /// expect a PROXY protocol (v1 or v2) header from webserver trusted proxies, or from every peer if there are none
+    #[arg(long)]
+    webserver_proxy_protocol: bool,
+
+    /// only accept callers of a tunnel from a network ("TUNNEL=CIDR")
+    #[arg(long, value_parser = acl::parse_tunnel_net)]
+    tunnel_allow: Vec<(String, IpNet)>,
+
+    /// never accept callers of a tunnel from a network ("TUNNEL=CIDR")
+    #[arg(long, value_parser = acl::parse_tunnel_net)]
+    tunnel_deny: Vec<(String, IpNet)>,
+
+    /// webserver incoming filters, evaluated in order (e.g. "deny path=^/admin", "allow method=GET path=^/api/")
     #[arg(short = 'f', long)]
-    webserver_filters: Vec<Regex>,
+    webserver_filters: Vec<Filter>,
+
+    /// webserver status for filtered out requests
+    #[arg(long, default_value = "404")]
+    webserver_filters_status: StatusCode,
+
+    /// webserver token bucket rate limits (e.g. "rate=10/s burst=20 per=ip", "path=^/upload rate=1/m")
+    #[arg(long)]
+    webserver_rate_limits: Vec<Limit>,
 
     /// webserver timeout in seconds
     #[arg(short = 't', long, default_value_t = 60)]
     webserver_timeout: u64,
+
+    /// webserver timeouts (e.g. "connect=2s,ttfb=10s,idle=30s"), also enforced by porcoc; total defaults to --webserver-timeout
+    #[arg(long)]
+    timeouts: Option<Timeouts>,
+
+    /// timeouts of a tunnel ("TUNNEL=TIMEOUTS"), overriding --timeouts and overridden by filter ones
+    #[arg(long, value_parser = parse_tunnel_timeouts)]
+    tunnel_timeouts: Vec<(String, Timeouts)>,
+
+    /// leave internal details out of error responses, only telling the kind of error
+    #[arg(long)]
+    hide_error_details: bool,
+
+    /// error page template for a status ("STATUS=FILE", .html or .json), picked on Accept, replacing
+    /// {{status}}, {{reason}}, {{title}}, {{kind}}, {{detail}} and {{request_id}}
+    #[arg(long, value_parser = error_pages::parse)]
+    error_page: Vec<(StatusCode, PathBuf)>,
+
+    /// only forward these request headers
+    #[arg(long)]
+    request_headers_allow: Vec<HeaderName>,
+
+    /// never forward these request headers
+    #[arg(long)]
+    request_headers_deny: Vec<HeaderName>,
+
+    /// remove these request headers after adding forwarding headers
+    #[arg(long)]
+    request_headers_remove: Vec<HeaderName>,
+
+    /// set a request header ("Name: value")
+    #[arg(long)]
+    request_headers_set: Vec<HeaderRule>,
+
+    /// add a request header ("Name: value")
+    #[arg(long)]
+    request_headers_add: Vec<HeaderRule>,
+
+    /// only forward these response headers
+    #[arg(long)]
+    response_headers_allow: Vec<HeaderName>,
+
+    /// never forward these response headers
+    #[arg(long)]
+    response_headers_deny: Vec<HeaderName>,
+
+    /// remove these response headers
+    #[arg(long)]
+    response_headers_remove: Vec<HeaderName>,
+
+    /// set a response header ("Name: value")
+    #[arg(long)]
+    response_headers_set: Vec<HeaderRule>,
+
+    /// add a response header ("Name: value")
+    #[arg(long)]
+    response_headers_add: Vec<HeaderRule>,
 }
 
 #[tokio::main]
 async fn main() -> anyhow::Result<()> {
-    tracing_subscriber::fmt::init();
-
     let args = Args::parse();
+    let _telemetry = common::telemetry::init("porcod", args.otlp_endpoint)?;
 
-    let (tx, rx) = channel(1);
+    let mut tunnel_acls = HashMap::<_, Acl>::new();
+    for (tunnel, net) in args.tunnel_allow {
+        tunnel_acls.entry(tunnel).or_default().allow.push(net);
+    }
+    for (tunnel, net) in args.tunnel_deny {
+        tunnel_acls.entry(tunnel).or_default().deny.push(net);
+    }
+
+    let access_log = args
+        .access_log
+        .map(|format| match &args.access_log_file {
+            Some(file) => AccessLog::file(format, file, args.access_log_rotation),
+            None => Ok(AccessLog::stdout(format)),
+        })
+        .transpose()?;
+
+    let (tx, rx) = channel(args.queue_depth);
+    let health = Arc::new(Health::new(args.ready_tunnels));
+    let inner = grpc::Inner::new(
+        rx,
+        args.tunnel_queue_depth,
+        args.tunnel_max_in_flight,
+        health.clone(),
+    );
+    let admin_config = admin::Config {
+        health: health.clone(),
+        admin: inner.admin(),
+        token: args
+            .admin_token_file
+            .map(fs::read_to_string)
+            .transpose()?
+            .map(|token| token.trim().to_owned())
+            .filter(|token| !token.is_empty()),
+    };
 
     tokio::select! {
         res = webserver::run(
             args.webserver_addr,
             args.webserver_certs.zip(args.webserver_private_key).map(load_certs).transpose()?,
-            args.webserver_filters,
-            Duration::from_secs(args.webserver_timeout),
+            webserver::Config {
+                acl: Acl {
+                    allow: args.webserver_allow,
+                    deny: args.webserver_deny,
+                },
+                trusted_proxies: args.webserver_trusted_proxies,
+                proxy_protocol: args.webserver_proxy_protocol,
+                tunnel_acls,
+                filters: args.webserver_filters,
+                filters_status: args.webserver_filters_status,
+                rate_limiter: Limiter::new(args.webserver_rate_limits),
+                timeout: Duration::from_secs(args.webserver_timeout),
+                timeouts: args.timeouts.unwrap_or_default(),
+                tunnel_timeouts: args.tunnel_timeouts.into_iter().collect(),
+                hide_error_details: args.hide_error_details,
+                error_pages: ErrorPages::load(args.error_page)?,
+                request_headers: HeaderPolicy {
+                    allow: args.request_headers_allow,
+                    deny: args.request_headers_deny,
+                    remove: args.request_headers_remove,
+                    set: args.request_headers_set,
+                    add: args.request_headers_add,
+                },
+                response_headers: HeaderPolicy {
+                    allow: args.response_headers_allow,
+                    deny: args.response_headers_deny,
+                    remove: args.response_headers_remove,
+                    set: args.response_headers_set,
+                    add: args.response_headers_add,
+                },
+                access_log,
+                health: health.clone(),
+            },
             tx
         ) => res,
         res = grpc::run(
             args.grpc_addr,
             args.grpc_certs.zip(args.grpc_private_key).map(load_certs).transpose()?,
-            rx
+            grpc::Config {
+                acl: Acl {
+                    allow: args.grpc_allow,
+                    deny: args.grpc_deny,
+                },
+                proxy_protocol: args.grpc_proxy_protocol,
+                trusted_proxies: args.grpc_trusted_proxies,
+                health,
+            },
+            inner
         ) => res,
+        res = async {
+            match args.metrics_addr {
+                Some(addr) => Ok(common::metrics::serve(addr).await?),
+                None => std::future::pending().await,
+            }
+        } => res,
+        res = async {
+            match args.admin_addr {
+                Some(addr) => Ok(admin::serve(addr, admin_config).await?),
+                None => std::future::pending().await,
+            }
+        } => res,
     }
 }
 
+/// Parses a `TUNNEL=TIMEOUTS` pair
+fn parse_tunnel_timeouts(value: &str) -> Result<(String, Timeouts), String> {
+    let (tunnel, timeouts) = value
+        .split_once('=')
+        .ok_or_else(|| "expected `TUNNEL=TIMEOUTS`".to_owned())?;
+    Ok((
+        tunnel.to_owned(),
+        Timeouts::from_str(timeouts).map_err(|err| err.to_string())?,
+    ))
+}
+
 pub fn load_certs(
     (certs, private_key): (PathBuf, PathBuf),
 ) -> io::Result<(Vec<CertificateDer<'static>>, PrivateKeyDer<'static>)> {
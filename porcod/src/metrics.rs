@@ -0,0 +1,101 @@
+use std::sync::LazyLock;
+
+use prometheus::{
+    register_histogram_vec, register_int_counter_vec, register_int_gauge, register_int_gauge_vec,
+    HistogramVec, IntCounterVec, IntGauge, IntGaugeVec,
+};
+
+// metrics are registered on first use, and the names are constants, so registration can't fail
+
+pub static REQUESTS: LazyLock<IntCounterVec> = LazyLock::new(|| {
+    register_int_counter_vec!(
+        "porcod_requests_total",
+        "Requests answered to callers",
+        &["method", "status", "tunnel"]
+    )
+    .unwrap()
+});
+
+pub static REQUEST_DURATION: LazyLock<HistogramVec> = LazyLock::new(|| {
+    register_histogram_vec!(
+        "porcod_request_duration_seconds",
+        "Time to answer callers, as observed by porcod",
+        &["tunnel"]
+    )
+    .unwrap()
+});
+
+pub static TUNNEL_DURATION: LazyLock<HistogramVec> = LazyLock::new(|| {
+    register_histogram_vec!(
+        "porcod_tunnel_duration_seconds",
+        "Time from queueing a request to receiving its response from porcoc",
+        &["tunnel"]
+    )
+    .unwrap()
+});
+
+pub static REQUEST_BYTES: LazyLock<IntCounterVec> = LazyLock::new(|| {
+    register_int_counter_vec!(
+        "porcod_request_bytes_total",
+        "Request body bytes received from callers",
+        &["tunnel"]
+    )
+    .unwrap()
+});
+
+pub static RESPONSE_BYTES: LazyLock<IntCounterVec> = LazyLock::new(|| {
+    register_int_counter_vec!(
+        "porcod_response_bytes_total",
+        "Response body bytes sent to callers",
+        &["tunnel"]
+    )
+    .unwrap()
+});
+
+pub static SESSIONS: LazyLock<IntGaugeVec> = LazyLock::new(|| {
+    register_int_gauge_vec!("porcod_sessions", "Connected porcoc sessions", &["tunnel"]).unwrap()
+});
+
+pub static QUEUED: LazyLock<IntGaugeVec> = LazyLock::new(|| {
+    register_int_gauge_vec!(
+        "porcod_queued_requests",
+        "Requests waiting for a porcoc session to pick them up",
+        &["tunnel"]
+    )
+    .unwrap()
+});
+
+pub static PENDING: LazyLock<IntGauge> = LazyLock::new(|| {
+    register_int_gauge!(
+        "porcod_pending_requests",
+        "Requests waiting for their response"
+    )
+    .unwrap()
+});
+
+pub static TLS_HANDSHAKE_FAILURES: LazyLock<IntCounterVec> = LazyLock::new(|| {
+    register_int_counter_vec!(
+        "porcod_tls_handshake_failures_total",
+        "Failed TLS handshakes",
+        &["listener"]
+    )
+    .unwrap()
+});
+
+pub static TIMEOUTS: LazyLock<IntCounterVec> = LazyLock::new(|| {
+    register_int_counter_vec!(
+        "porcod_timeouts_total",
+        "Requests expired before their response",
+        &["tunnel"]
+    )
+    .unwrap()
+});
+
+pub static REJECTIONS: LazyLock<IntCounterVec> = LazyLock::new(|| {
+    register_int_counter_vec!(
+        "porcod_rejections_total",
+        "Connections and requests rejected by porcod",
+        &["reason"]
+    )
+    .unwrap()
+});
@@ -0,0 +1,297 @@
+use std::{
+    io,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+    str::FromStr,
+    time::Duration,
+};
+
+use ipnet::IpNet;
+use tokio::{
+    io::{AsyncRead, AsyncReadExt},
+    net::TcpStream,
+    time::timeout,
+};
+
+use crate::acl;
+
+const V2_SIGNATURE: &[u8; 12] = b"\r\n\r\n\0\r\nQUIT\n";
+// "PROXY UNKNOWN " + 2 * 39 addresses + 2 * 5 ports + 3 spaces + "\r\n"
+const V1_MAX_LENGTH: usize = 107;
+const HEADER_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Reads the PROXY protocol header when `peer` is expected to send one, returning the real source address
+///
+/// With no trusted proxies every peer must send the header
+pub async fn accept(
+    stream: &mut TcpStream,
+    peer: SocketAddr,
+    trusted_proxies: &[IpNet],
+) -> io::Result<SocketAddr> {
+    if !trusted_proxies.is_empty() && !acl::contains(trusted_proxies, peer.ip()) {
+        return Ok(peer);
+    }
+
+    let source = timeout(HEADER_TIMEOUT, read_header(stream))
+        .await
+        .map_err(|_| io::Error::new(io::ErrorKind::TimedOut, "PROXY header timed out"))??;
+    // LOCAL and UNKNOWN connections come from the proxy itself
+    Ok(source.unwrap_or(peer))
+}
+
+/// Reads a v1 or v2 PROXY protocol header, without consuming anything after it
+async fn read_header<R: AsyncRead + Unpin>(stream: &mut R) -> io::Result<Option<SocketAddr>> {
+    // the shortest v1 header is 15 bytes long, so we can't over-read
+    let mut signature = [0; 12];
+    stream.read_exact(&mut signature).await?;
+
+    if &signature == V2_SIGNATURE {
+        read_v2(stream).await
+    } else if signature.starts_with(b"PROXY ") {
+        read_v1(stream, signature.to_vec()).await
+    } else {
+        Err(invalid_data("missing PROXY header"))
+    }
+}
+
+async fn read_v1<R: AsyncRead + Unpin>(
+    stream: &mut R,
+    mut line: Vec<u8>,
+) -> io::Result<Option<SocketAddr>> {
+    while !line.ends_with(b"\r\n") {
+        if line.len() >= V1_MAX_LENGTH {
+            return Err(invalid_data("PROXY header too long"));
+        }
+        line.push(stream.read_u8().await?);
+    }
+
+    let line = std::str::from_utf8(&line[..line.len() - 2])
+        .map_err(|_| invalid_data("invalid PROXY header"))?;
+    let mut parts = line.split(' ').skip(1);
+    match parts.next() {
+        Some("TCP4" | "TCP6") => {}
+        Some("UNKNOWN") => return Ok(None),
+        _ => return Err(invalid_data("invalid PROXY protocol family")),
+    }
+    let (Some(source), Some(_), Some(port)) = (parts.next(), parts.next(), parts.next()) else {
+        return Err(invalid_data("truncated PROXY header"));
+    };
+    let source = IpAddr::from_str(source).map_err(|_| invalid_data("invalid PROXY source"))?;
+    let port = u16::from_str(port).map_err(|_| invalid_data("invalid PROXY source port"))?;
+    Ok(Some(SocketAddr::new(source, port)))
+}
+
+async fn read_v2<R: AsyncRead + Unpin>(stream: &mut R) -> io::Result<Option<SocketAddr>> {
+    let version_command = stream.read_u8().await?;
+    let family = stream.read_u8().await?;
+    let mut addresses = vec![0; stream.read_u16().await? as usize];
+    stream.read_exact(&mut addresses).await?;
+
+    if version_command >> 4 != 2 {
+        return Err(invalid_data("unsupported PROXY protocol version"));
+    }
+    match version_command & 0x0f {
+        // LOCAL
+        0 => return Ok(None),
+        // PROXY
+        1 => {}
+        _ => return Err(invalid_data("invalid PROXY command")),
+    }
+
+    // the high nibble is the address family, TLVs after the addresses are ignored
+    match family >> 4 {
+        1 if addresses.len() >= 12 => {
+            let ip = <[u8; 4]>::try_from(&addresses[..4]).unwrap();
+            let port = u16::from_be_bytes([addresses[8], addresses[9]]);
+            Ok(Some(SocketAddr::new(Ipv4Addr::from(ip).into(), port)))
+        }
+        2 if addresses.len() >= 36 => {
+            let ip = <[u8; 16]>::try_from(&addresses[..16]).unwrap();
+            let port = u16::from_be_bytes([addresses[32], addresses[33]]);
+            Ok(Some(SocketAddr::new(Ipv6Addr::from(ip).into(), port)))
+        }
+        // UNSPEC and UNIX sockets carry no usable address
+        0 | 3 => Ok(None),
+        _ => Err(invalid_data("truncated PROXY addresses")),
+    }
+}
+
+fn invalid_data(error: &'static str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, error)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Parses `input`, returning the result and what's left unread
+    async fn parse(input: &[u8]) -> (io::Result<Option<SocketAddr>>, &[u8]) {
+        let mut rest = input;
+        let res = read_header(&mut rest).await;
+        (res, rest)
+    }
+
+    fn v2(command: u8, family: u8, addresses: &[u8]) -> Vec<u8> {
+        let mut header = V2_SIGNATURE.to_vec();
+        header.push(0x20 | command);
+        header.push(family);
+        header.extend((addresses.len() as u16).to_be_bytes());
+        header.extend(addresses);
+        header
+    }
+
+    #[tokio::test]
+    async fn v1_tcp4() {
+        let (res, rest) =
+            parse(b"PROXY TCP4 192.0.2.1 198.51.100.1 56324 443\r\nGET / HTTP/1.1\r\n").await;
+        assert_eq!(res.unwrap(), Some("192.0.2.1:56324".parse().unwrap()));
+        assert_eq!(rest, b"GET / HTTP/1.1\r\n");
+    }
+
+    #[tokio::test]
+    async fn v1_tcp6() {
+        let (res, rest) = parse(b"PROXY TCP6 2001:db8::1 2001:db8::2 56324 443\r\n").await;
+        assert_eq!(res.unwrap(), Some("[2001:db8::1]:56324".parse().unwrap()));
+        assert!(rest.is_empty());
+    }
+
+    #[tokio::test]
+    async fn v1_unknown() {
+        let (res, rest) = parse(b"PROXY UNKNOWN ignored\r\nhello").await;
+        assert_eq!(res.unwrap(), None);
+        assert_eq!(rest, b"hello");
+    }
+
+    #[tokio::test]
+    async fn v1_longest() {
+        let addresses =
+            "ffff:ffff:ffff:ffff:ffff:ffff:ffff:ffff ffff:ffff:ffff:ffff:ffff:ffff:ffff:ffff";
+        let line = format!("PROXY UNKNOWN {addresses} 65535 65535\r\n");
+        assert_eq!(line.len(), V1_MAX_LENGTH);
+        let (res, rest) = parse(line.as_bytes()).await;
+        assert_eq!(res.unwrap(), None);
+        assert!(rest.is_empty());
+
+        let line = format!("PROXY TCP6 {addresses} 65535 65535\r\n");
+        let (res, _) = parse(line.as_bytes()).await;
+        assert!(res.unwrap().is_some());
+    }
+
+    #[tokio::test]
+    async fn v1_too_long() {
+        let mut line = b"PROXY UNKNOWN ".to_vec();
+        line.resize(200, b'x');
+        let (res, rest) = parse(&line).await;
+        assert_eq!(res.unwrap_err().kind(), io::ErrorKind::InvalidData);
+        // reading stops at the limit
+        assert_eq!(rest.len(), line.len() - V1_MAX_LENGTH);
+    }
+
+    #[tokio::test]
+    async fn v1_malformed() {
+        for line in [
+            &b"PROXY UDP4 192.0.2.1 198.51.100.1 56324 443\r\n"[..],
+            b"PROXY TCP4 192.0.2.1 198.51.100.1\r\n",
+            b"PROXY TCP4 not-an-ip 198.51.100.1 56324 443\r\n",
+            b"PROXY TCP4 192.0.2.1 198.51.100.1 65536 443\r\n",
+            b"PROXY TCP4 \xff\xfe 198.51.100.1 56324 443\r\n",
+            b"GET / HTTP/1.1\r\nHost: x\r\n",
+        ] {
+            let (res, _) = parse(line).await;
+            assert_eq!(res.unwrap_err().kind(), io::ErrorKind::InvalidData);
+        }
+    }
+
+    #[tokio::test]
+    async fn v1_truncated_stream() {
+        let (res, _) = parse(b"PROXY TCP4 192.0.2.1").await;
+        assert_eq!(res.unwrap_err().kind(), io::ErrorKind::UnexpectedEof);
+    }
+
+    #[tokio::test]
+    async fn v2_proxy_ipv4() {
+        let mut header = v2(
+            1,
+            0x11,
+            &[192, 0, 2, 1, 198, 51, 100, 1, 0xdc, 0x04, 0x01, 0xbb],
+        );
+        header.extend(b"hello");
+        let (res, rest) = parse(&header).await;
+        assert_eq!(res.unwrap(), Some("192.0.2.1:56324".parse().unwrap()));
+        assert_eq!(rest, b"hello");
+    }
+
+    #[tokio::test]
+    async fn v2_proxy_ipv6() {
+        let mut addresses = vec![0x20, 0x01, 0x0d, 0xb8];
+        addresses.resize(15, 0);
+        addresses.push(1);
+        addresses.resize(32, 0);
+        addresses.extend([0xdc, 0x04, 0x01, 0xbb]);
+        let header = v2(1, 0x21, &addresses);
+        let (res, rest) = parse(&header).await;
+        assert_eq!(res.unwrap(), Some("[2001:db8::1]:56324".parse().unwrap()));
+        assert!(rest.is_empty());
+    }
+
+    #[tokio::test]
+    async fn v2_tlvs_ignored() {
+        let mut addresses = vec![192, 0, 2, 1, 198, 51, 100, 1, 0xdc, 0x04, 0x01, 0xbb];
+        // a NOOP TLV
+        addresses.extend([0x04, 0x00, 0x02, 0x00, 0x00]);
+        let mut header = v2(1, 0x11, &addresses);
+        header.extend(b"hello");
+        let (res, rest) = parse(&header).await;
+        assert_eq!(res.unwrap(), Some("192.0.2.1:56324".parse().unwrap()));
+        assert_eq!(rest, b"hello");
+    }
+
+    #[tokio::test]
+    async fn v2_unspec_and_unix() {
+        for family in [0x00, 0x31] {
+            let header = v2(1, family, &[0; 216]);
+            let (res, rest) = parse(&header).await;
+            assert_eq!(res.unwrap(), None);
+            assert!(rest.is_empty());
+        }
+    }
+
+    #[tokio::test]
+    async fn v2_local() {
+        // LOCAL ignores the addresses, whatever they are
+        let mut header = v2(0, 0x11, &[1, 2, 3]);
+        header.extend(b"hello");
+        let (res, rest) = parse(&header).await;
+        assert_eq!(res.unwrap(), None);
+        assert_eq!(rest, b"hello");
+    }
+
+    #[tokio::test]
+    async fn v2_truncated_addresses() {
+        for (family, len) in [(0x11, 11), (0x21, 35)] {
+            let header = v2(1, family, &vec![0; len]);
+            let (res, rest) = parse(&header).await;
+            assert_eq!(res.unwrap_err().kind(), io::ErrorKind::InvalidData);
+            assert!(rest.is_empty());
+        }
+    }
+
+    #[tokio::test]
+    async fn v2_truncated_stream() {
+        let mut header = v2(1, 0x11, &[0; 12]);
+        header.truncate(header.len() - 1);
+        let (res, _) = parse(&header).await;
+        assert_eq!(res.unwrap_err().kind(), io::ErrorKind::UnexpectedEof);
+    }
+
+    #[tokio::test]
+    async fn v2_invalid() {
+        let mut version = v2(1, 0x11, &[0; 12]);
+        version[12] = 0x11;
+        let command = v2(2, 0x11, &[0; 12]);
+        let family = v2(1, 0x41, &[0; 12]);
+        for header in [version, command, family] {
+            let (res, _) = parse(&header).await;
+            assert_eq!(res.unwrap_err().kind(), io::ErrorKind::InvalidData);
+        }
+    }
+}
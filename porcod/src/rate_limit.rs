@@ -0,0 +1,499 @@
+use std::{
+    collections::HashMap,
+    future::Future,
+    net::IpAddr,
+    pin::Pin,
+    str::FromStr,
+    sync::{Arc, Mutex},
+    task::{Context, Poll},
+    time::Duration,
+};
+
+use common::{
+    headers::X_REQUEST_ID,
+    problem::{Kind, Problem},
+};
+use http::{
+    header::{ACCEPT, RETRY_AFTER},
+    HeaderValue, Request, Response,
+};
+use hyper::{body::Bytes, service::Service};
+use pin_project_lite::pin_project;
+use regex::Regex;
+use tokio::time::Instant;
+
+use crate::{
+    acl, metrics,
+    webserver::{self, Call, Config},
+};
+
+// above this many buckets, full ones are dropped since they're the same as new ones
+const PRUNE_THRESHOLD: usize = 10_000;
+// pruning scans every bucket, so it's done at most this often
+const PRUNE_INTERVAL: Duration = Duration::from_secs(10);
+
+/// A token bucket rate limit
+///
+/// Limits are written as whitespace separated options:
+/// - `rate=N/UNIT` tokens refilled per `s`econd, `m`inute or `h`our, required
+/// - `burst=N` bucket size, defaults to the rate
+/// - `path=REGEX` only limits matching paths
+/// - `tunnel=NAME` only limits requests routed to the tunnel
+/// - `per=ip` or `per=tunnel` keeps a bucket per client address or per tunnel, otherwise matching requests share one
+///
+/// e.g. `rate=10/s burst=20 per=ip`, `path=^/upload rate=1/m`, `rate=100/s per=tunnel`
+#[derive(Debug, Clone)]
+pub struct Limit {
+    rate: f64,
+    burst: f64,
+    path: Option<Regex>,
+    tunnel: Option<String>,
+    per: Per,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Per {
+    Limit,
+    Ip,
+    Tunnel,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+enum Key {
+    Shared,
+    Ip(IpAddr),
+    Tunnel(String),
+}
+
+#[derive(Debug)]
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+/// Rate limits with their buckets, shared by every connection
+#[derive(Debug)]
+pub struct Limiter {
+    limits: Vec<Limit>,
+    buckets: Mutex<Buckets>,
+}
+
+#[derive(Debug)]
+struct Buckets {
+    buckets: HashMap<(usize, Key), Bucket>,
+    pruned: Instant,
+}
+
+impl Limiter {
+    pub fn new(limits: Vec<Limit>) -> Self {
+        Self {
+            limits,
+            buckets: Mutex::new(Buckets {
+                buckets: HashMap::new(),
+                pruned: Instant::now(),
+            }),
+        }
+    }
+
+    /// Takes a token from every matching bucket, or returns how long to wait if any of them is empty
+    fn check(
+        &self,
+        path: &str,
+        client_ip: IpAddr,
+        tunnel: &str,
+        now: Instant,
+    ) -> Result<(), Duration> {
+        let mut state = self.buckets.lock().unwrap();
+        let Buckets { buckets, pruned } = &mut *state;
+        if buckets.len() > PRUNE_THRESHOLD && now.duration_since(*pruned) >= PRUNE_INTERVAL {
+            buckets.retain(|(index, _), bucket| {
+                bucket.refill(&self.limits[*index], now) < self.limits[*index].burst
+            });
+            *pruned = now;
+        }
+
+        let mut matching = vec![];
+        for (index, limit) in self.limits.iter().enumerate() {
+            if limit
+                .path
+                .as_ref()
+                .is_some_and(|path_re| !path_re.is_match(path))
+                || limit.tunnel.as_ref().is_some_and(|name| name != tunnel)
+            {
+                continue;
+            }
+            let key = match limit.per {
+                Per::Limit => Key::Shared,
+                Per::Ip => Key::Ip(client_ip),
+                Per::Tunnel => Key::Tunnel(tunnel.to_owned()),
+            };
+            matching.push((index, key));
+        }
+
+        // tokens are taken only if every bucket has one, so rejected requests don't count
+        let mut retry_after = Duration::ZERO;
+        for (index, key) in &matching {
+            let limit = &self.limits[*index];
+            let bucket = buckets
+                .entry((*index, key.clone()))
+                .or_insert_with(|| Bucket {
+                    tokens: limit.burst,
+                    updated: now,
+                });
+            let tokens = bucket.refill(limit, now);
+            if tokens < 1.0 {
+                retry_after = retry_after.max(Duration::from_secs_f64((1.0 - tokens) / limit.rate));
+            }
+        }
+        if !retry_after.is_zero() {
+            return Err(retry_after);
+        }
+        for key in matching {
+            if let Some(bucket) = buckets.get_mut(&key) {
+                bucket.tokens -= 1.0;
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Bucket {
+    fn refill(&mut self, limit: &Limit, now: Instant) -> f64 {
+        let elapsed = now.saturating_duration_since(self.updated).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * limit.rate).min(limit.burst);
+        self.updated = now;
+        self.tokens
+    }
+}
+
+/// Answers `429 Too Many Requests` in front of the webserver service when a rate limit is exceeded
+///
+/// Only requests passing the access lists and filters take tokens, denied ones go through to the webserver service untouched
+#[derive(Debug, Clone)]
+pub struct RateLimit<S> {
+    inner: S,
+    config: Arc<Config>,
+    connection: Arc<common::Connection>,
+}
+
+impl<S> RateLimit<S> {
+    pub fn new(inner: S, config: Arc<Config>, connection: Arc<common::Connection>) -> Self {
+        Self {
+            inner,
+            config,
+            connection,
+        }
+    }
+}
+
+impl<S, B, ResBody> Service<Request<B>> for RateLimit<S>
+where
+    S: Service<Request<B>, Response = Response<ResBody>>,
+    ResBody: From<Bytes> + hyper::body::Body,
+{
+    type Response = Response<ResBody>;
+    type Error = S::Error;
+    type Future = ResponseFuture<S::Future, ResBody>;
+
+    fn call(&self, req: Request<B>) -> Self::Future {
+        let config = &self.config;
+        if config.rate_limiter.limits.is_empty() {
+            return ResponseFuture::Inner {
+                future: self.inner.call(req),
+            };
+        }
+        let client_ip = acl::client_ip(
+            self.connection.remote_addr.ip(),
+            req.headers(),
+            &config.trusted_proxies,
+        );
+        // the webserver service rejects them again, accounting the reason
+        let Ok((tunnel, _)) = webserver::admit(config, client_ip, &req) else {
+            return ResponseFuture::Inner {
+                future: self.inner.call(req),
+            };
+        };
+
+        match config
+            .rate_limiter
+            .check(req.uri().path(), client_ip, &tunnel, Instant::now())
+        {
+            Ok(()) => ResponseFuture::Inner {
+                future: self.inner.call(req),
+            },
+            Err(retry_after) => {
+                metrics::REJECTIONS.with_label_values(&["rate_limit"]).inc();
+                // at least a second, since the header has no fractions
+                let seconds =
+                    (retry_after.as_secs() + u64::from(retry_after.subsec_nanos() > 0)).max(1);
+                // rejected before reaching the webserver service, which otherwise tags and accounts the response
+                let call = Call::new(config, &self.connection, &req, tunnel);
+                let mut response = webserver::problem_response(
+                    config,
+                    Problem::new(Kind::RateLimited).with_detail(format!("Retry in {seconds}s")),
+                    req.headers().get(ACCEPT),
+                    call.request_id(),
+                );
+                response
+                    .headers_mut()
+                    .insert(RETRY_AFTER, HeaderValue::from(seconds));
+                if let Ok(value) = HeaderValue::from_str(call.request_id()) {
+                    response.headers_mut().insert(X_REQUEST_ID, value);
+                }
+                call.record(&response, config.access_log.as_ref());
+                ResponseFuture::Limited {
+                    response: Some(response),
+                }
+            }
+        }
+    }
+}
+
+pin_project! {
+    #[project = ResponseFutureProj]
+    pub enum ResponseFuture<F, B> {
+        Limited { response: Option<Response<B>> },
+        Inner { #[pin] future: F },
+    }
+}
+
+impl<F, B, E> Future for ResponseFuture<F, B>
+where
+    F: Future<Output = Result<Response<B>, E>>,
+{
+    type Output = Result<Response<B>, E>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        match self.project() {
+            ResponseFutureProj::Limited { response } => {
+                Poll::Ready(Ok(response.take().expect("polled after completion")))
+            }
+            ResponseFutureProj::Inner { future } => future.poll(cx),
+        }
+    }
+}
+
+impl FromStr for Limit {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (mut rate, mut burst, mut path, mut tunnel, mut per) =
+            (None, None, None, None, Per::Limit);
+        for token in s.split_whitespace() {
+            let (key, value) = token
+                .split_once('=')
+                .ok_or_else(|| Error::Key(token.into()))?;
+            match key {
+                "rate" => rate = Some(parse_rate(value).ok_or_else(|| Error::Rate(value.into()))?),
+                "burst" => {
+                    burst = Some(
+                        u32::from_str(value)
+                            .ok()
+                            .filter(|burst| *burst > 0)
+                            .ok_or_else(|| Error::Burst(value.into()))?,
+                    )
+                }
+                "path" => path = Some(Regex::new(value)?),
+                "tunnel" => tunnel = Some(value.to_owned()),
+                "per" => {
+                    per = match value {
+                        "ip" => Per::Ip,
+                        "tunnel" => Per::Tunnel,
+                        _ => return Err(Error::Per(value.into())),
+                    }
+                }
+                _ => return Err(Error::Key(key.into())),
+            }
+        }
+        let (count, rate) = rate.ok_or(Error::MissingRate)?;
+
+        Ok(Self {
+            rate,
+            burst: f64::from(burst.unwrap_or(count)),
+            path,
+            tunnel,
+            per,
+        })
+    }
+}
+
+// parses `N/UNIT` into the count and the tokens per second
+fn parse_rate(value: &str) -> Option<(u32, f64)> {
+    let (count, unit) = value.split_once('/').unwrap_or((value, "s"));
+    let count = u32::from_str(count).ok().filter(|count| *count > 0)?;
+    let seconds = match unit {
+        "s" => 1.0,
+        "m" => 60.0,
+        "h" => 3600.0,
+        _ => return None,
+    };
+    Some((count, f64::from(count) / seconds))
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+    #[error("rate limit without rate")]
+    MissingRate,
+    #[error("unknown rate limit option `{0}`")]
+    Key(String),
+    #[error("invalid rate `{0}`, expected `N/s`, `N/m` or `N/h`")]
+    Rate(String),
+    #[error("invalid burst `{0}`")]
+    Burst(String),
+    #[error("invalid bucket key `{0}`, expected `ip` or `tunnel`")]
+    Per(String),
+    #[error(transparent)]
+    Regex(#[from] regex::Error),
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::Ipv4Addr;
+
+    use super::*;
+
+    const CALLER: IpAddr = IpAddr::V4(Ipv4Addr::new(10, 1, 2, 3));
+
+    fn limiter(limits: &[&str]) -> Limiter {
+        Limiter::new(limits.iter().map(|limit| limit.parse().unwrap()).collect())
+    }
+
+    fn ms(millis: u64) -> Duration {
+        Duration::from_millis(millis)
+    }
+
+    #[test]
+    fn parse() {
+        let limit = Limit::from_str("rate=10/m").unwrap();
+        assert_eq!(limit.rate, 10.0 / 60.0);
+        assert_eq!(limit.burst, 10.0);
+        assert_eq!(limit.per, Per::Limit);
+
+        let limit = Limit::from_str("path=^/upload rate=1/h burst=5 per=ip tunnel=files").unwrap();
+        assert_eq!(limit.burst, 5.0);
+        assert_eq!(limit.per, Per::Ip);
+        assert_eq!(limit.tunnel.as_deref(), Some("files"));
+
+        assert!(matches!(
+            Limit::from_str("burst=5"),
+            Err(Error::MissingRate)
+        ));
+        assert!(matches!(Limit::from_str("rate=0/s"), Err(Error::Rate(_))));
+        assert!(matches!(Limit::from_str("rate=1/d"), Err(Error::Rate(_))));
+        assert!(matches!(
+            Limit::from_str("rate=1 burst=0"),
+            Err(Error::Burst(_))
+        ));
+        assert!(matches!(
+            Limit::from_str("rate=1 per=host"),
+            Err(Error::Per(_))
+        ));
+        assert!(matches!(
+            Limit::from_str("rate=1 size=2"),
+            Err(Error::Key(_))
+        ));
+        assert!(matches!(
+            Limit::from_str("rate=1 path=(unclosed"),
+            Err(Error::Regex(_))
+        ));
+    }
+
+    #[test]
+    fn refill() {
+        let limiter = limiter(&["rate=2/s"]);
+        let now = Instant::now();
+        assert_eq!(limiter.check("/", CALLER, "t", now), Ok(()));
+        assert_eq!(limiter.check("/", CALLER, "t", now), Ok(()));
+        assert_eq!(limiter.check("/", CALLER, "t", now), Err(ms(500)));
+        assert_eq!(limiter.check("/", CALLER, "t", now + ms(250)), Err(ms(250)));
+        assert_eq!(limiter.check("/", CALLER, "t", now + ms(500)), Ok(()));
+        assert_eq!(limiter.check("/", CALLER, "t", now + ms(500)), Err(ms(500)));
+
+        // a long pause refills up to the burst only
+        let later = now + Duration::from_secs(60);
+        assert_eq!(limiter.check("/", CALLER, "t", later), Ok(()));
+        assert_eq!(limiter.check("/", CALLER, "t", later), Ok(()));
+        assert!(limiter.check("/", CALLER, "t", later).is_err());
+
+        // time going backwards doesn't add tokens
+        assert!(limiter.check("/", CALLER, "t", now).is_err());
+    }
+
+    #[test]
+    fn burst() {
+        let limiter = limiter(&["rate=1/s burst=3"]);
+        let now = Instant::now();
+        for _ in 0..3 {
+            assert_eq!(limiter.check("/", CALLER, "t", now), Ok(()));
+        }
+        assert_eq!(limiter.check("/", CALLER, "t", now), Err(ms(1000)));
+    }
+
+    #[test]
+    fn keys() {
+        let limiter = limiter(&[
+            "rate=1/s per=ip",
+            "path=^/upload rate=1/m per=tunnel",
+            "tunnel=api rate=1/h",
+        ]);
+        let other = IpAddr::V4(Ipv4Addr::new(10, 1, 2, 4));
+        let now = Instant::now();
+        assert_eq!(limiter.check("/", CALLER, "t", now), Ok(()));
+        assert!(limiter.check("/", CALLER, "t", now).is_err());
+        assert_eq!(limiter.check("/", other, "t", now), Ok(()));
+
+        let now = now + Duration::from_secs(1);
+        assert_eq!(limiter.check("/upload", CALLER, "files", now), Ok(()));
+        assert_eq!(limiter.check("/upload", other, "t", now), Ok(()));
+        let now = now + Duration::from_secs(1);
+        assert_eq!(
+            limiter.check("/upload", other, "files", now),
+            Err(Duration::from_secs(59))
+        );
+
+        let now = now + Duration::from_secs(1);
+        assert_eq!(limiter.check("/", CALLER, "api", now), Ok(()));
+        assert_eq!(
+            limiter.check("/", other, "api", now),
+            Err(Duration::from_secs(3600))
+        );
+    }
+
+    #[test]
+    fn all_or_nothing() {
+        let limiter = limiter(&["rate=1/s", "path=^/upload rate=1/m"]);
+        let now = Instant::now();
+        assert_eq!(limiter.check("/upload", CALLER, "t", now), Ok(()));
+        // the shared bucket refills, the upload one doesn't
+        let now = now + Duration::from_secs(1);
+        assert!(limiter.check("/upload", CALLER, "t", now).is_err());
+        // so the rejected upload didn't take the shared token
+        assert_eq!(limiter.check("/", CALLER, "t", now), Ok(()));
+    }
+
+    #[test]
+    fn prune() {
+        let limiter = limiter(&["rate=1/s per=ip", "path=^/upload rate=1/m"]);
+        let now = Instant::now();
+        for i in 0..=PRUNE_THRESHOLD as u32 {
+            let client_ip = IpAddr::V4(Ipv4Addr::from(i));
+            assert_eq!(limiter.check("/", client_ip, "t", now), Ok(()));
+        }
+        let count = || limiter.buckets.lock().unwrap().buckets.len();
+        assert_eq!(count(), PRUNE_THRESHOLD + 1);
+
+        // not before the interval, even if every bucket is full again
+        let soon = now + PRUNE_INTERVAL / 2;
+        assert_eq!(limiter.check("/upload", CALLER, "t", soon), Ok(()));
+        assert_eq!(count(), PRUNE_THRESHOLD + 3);
+
+        // only full buckets go, the upload one is still refilling
+        let later = now + PRUNE_INTERVAL;
+        assert_eq!(limiter.check("/", CALLER, "t", later), Ok(()));
+        assert_eq!(count(), 2);
+        assert_eq!(
+            limiter.check("/upload", CALLER, "t", later),
+            Err(Duration::from_secs(55))
+        );
+    }
+}
@@ -1,9 +1,11 @@
 use std::{
     io,
+    net::SocketAddr,
     pin::Pin,
     task::{Context, Poll},
 };
 
+use http::uri::Scheme;
 use tokio::{
     io::{AsyncRead, AsyncWrite, ReadBuf},
     net::TcpStream,
@@ -18,6 +20,36 @@ pin_project_lite::pin_project! {
     }
 }
 
+impl Tls {
+    /// Collects the details of this connection
+    pub fn connection(&self, remote_addr: SocketAddr) -> io::Result<common::Connection> {
+        match self {
+            Self::Rustls { stream } => {
+                let (tcp, tls) = stream.get_ref();
+                Ok(common::Connection {
+                    remote_addr,
+                    local_port: tcp.local_addr()?.port(),
+                    scheme: Scheme::HTTPS,
+                    tls_version: tls
+                        .protocol_version()
+                        .and_then(|version| version.as_str())
+                        .map(str::to_owned),
+                    sni: tls.server_name().map(str::to_owned),
+                    alpn: tls.alpn_protocol().map(<[u8]>::to_vec),
+                })
+            }
+            Self::None { stream } => Ok(common::Connection {
+                remote_addr,
+                local_port: stream.local_addr()?.port(),
+                scheme: Scheme::HTTP,
+                tls_version: None,
+                sni: None,
+                alpn: None,
+            }),
+        }
+    }
+}
+
 impl AsyncRead for Tls {
     fn poll_read(
         self: Pin<&mut Self>,
@@ -1,14 +1,33 @@
-use std::{future::Future, net::SocketAddr, pin::Pin, sync::Arc, time::Duration};
+use std::{
+    collections::HashMap,
+    convert::Infallible,
+    future::Future,
+    net::{IpAddr, SocketAddr},
+    pin::Pin,
+    sync::Arc,
+    time::Duration,
+};
 
-use http::{header::HOST, Request, Response, StatusCode};
-use http_body_util::{BodyExt, Full};
+use common::{
+    body::{self, Body},
+    headers::{self, HeaderPolicy, X_REQUEST_ID},
+    problem::{Kind, Problem},
+    telemetry,
+    timeouts::{self, Timeouts},
+};
+use http::{
+    header::{ACCEPT, CONTENT_LENGTH, HOST, REFERER, TRAILER, USER_AGENT},
+    HeaderValue, Method, Request, Response, StatusCode, Uri, Version,
+};
 use hyper::{
     body::{Bytes, Incoming},
-    server::conn::http1,
     service,
 };
-use hyper_util::rt::TokioIo;
-use regex::Regex;
+use hyper_util::{
+    rt::{TokioExecutor, TokioIo},
+    server::conn::auto,
+};
+use ipnet::IpNet;
 use rustls::{
     pki_types::{CertificateDer, PrivateKeyDer},
     ServerConfig,
@@ -16,21 +35,55 @@ use rustls::{
 use tokio::{
     net::TcpListener,
     sync::{
-        mpsc::Sender,
+        mpsc::{error::TrySendError, Sender},
         oneshot::{self, error::RecvError},
     },
-    time::{error::Elapsed, timeout},
+    time::{error::Elapsed, timeout_at, Instant},
 };
 use tokio_rustls::TlsAcceptor;
-use tracing::{debug, error};
+use tracing::{debug, error, field, info_span, Instrument, Span};
 
-use crate::tls::Tls;
+use crate::{
+    access_log::{self, AccessLog},
+    acl::{self, Acl},
+    error_pages::ErrorPages,
+    filter::{self, Filter},
+    health::Health,
+    metrics, proxy_protocol,
+    rate_limit::{Limiter, RateLimit},
+    tls::Tls,
+    Reply, Served,
+};
+
+/// Webserver behavior, shared by every connection
+#[derive(Debug)]
+pub struct Config {
+    pub acl: Acl,
+    pub trusted_proxies: Vec<IpNet>,
+    /// expect a PROXY protocol header from trusted proxies, or from every peer if there are none
+    pub proxy_protocol: bool,
+    pub tunnel_acls: HashMap<String, Acl>,
+    pub filters: Vec<Filter>,
+    pub filters_status: StatusCode,
+    pub rate_limiter: Limiter,
+    /// total timeout, unless set by filters, tunnels or `timeouts`
+    pub timeout: Duration,
+    /// global timeouts, filter and tunnel ones take precedence
+    pub timeouts: Timeouts,
+    pub tunnel_timeouts: HashMap<String, Timeouts>,
+    pub request_headers: HeaderPolicy,
+    pub response_headers: HeaderPolicy,
+    pub access_log: Option<AccessLog>,
+    pub health: Arc<Health>,
+    /// keep error details out of problem responses
+    pub hide_error_details: bool,
+    pub error_pages: ErrorPages,
+}
 
 pub async fn run(
     addr: SocketAddr,
     cert: Option<(Vec<CertificateDer<'static>>, PrivateKeyDer<'static>)>,
-    filters: Vec<Regex>,
-    timeout: Duration,
+    config: Config,
     request_tx: Sender<crate::ChannelItem>,
 ) -> anyhow::Result<()> {
     // Set a process wide default crypto provider.
@@ -54,28 +107,68 @@ pub async fn run(
 
     let listener = TcpListener::bind(&addr).await?;
     debug!("Webserver listening on http://{}", addr);
+    config.health.webserver_bound();
 
-    let service = Service::new(filters, timeout, request_tx);
+    let config = Arc::new(config);
     loop {
-        let (stream, _) = listener.accept().await?;
-
+        let (mut stream, mut remote_addr) = listener.accept().await?;
         tokio::spawn({
             let tls_acceptor = tls_acceptor.clone();
-            let service = service.clone();
+            let config = config.clone();
+            let request_tx = request_tx.clone();
             async move {
+                if config.proxy_protocol {
+                    match proxy_protocol::accept(&mut stream, remote_addr, &config.trusted_proxies)
+                        .await
+                    {
+                        Ok(source) => remote_addr = source,
+                        Err(err) => {
+                            error!("Failed to read PROXY header from {remote_addr}: {err}");
+                            return;
+                        }
+                    }
+                }
+
+                // callers behind trusted proxies are checked on each request
+                if !acl::contains(&config.trusted_proxies, remote_addr.ip())
+                    && !config.acl.allows(remote_addr.ip())
+                {
+                    debug!("Rejected connection from {remote_addr}");
+                    metrics::REJECTIONS.with_label_values(&["acl"]).inc();
+                    return;
+                }
+
                 let io = match tls_acceptor {
                     Some(tls_acceptor) => match tls_acceptor.accept(stream).await {
                         Ok(stream) => Tls::Rustls { stream },
                         Err(err) => {
                             error!("failed to perform tls handshake: {err}");
+                            metrics::TLS_HANDSHAKE_FAILURES
+                                .with_label_values(&["webserver"])
+                                .inc();
                             return;
                         }
                     },
                     None => Tls::None { stream },
                 };
 
-                if let Err(err) = http1::Builder::new()
-                    .serve_connection(TokioIo::new(io), service)
+                let connection = match io.connection(remote_addr) {
+                    Ok(connection) => Arc::new(connection),
+                    Err(err) => {
+                        error!("Failed to read connection details: {err}");
+                        return;
+                    }
+                };
+
+                if let Err(err) = auto::Builder::new(TokioExecutor::new())
+                    .serve_connection(
+                        TokioIo::new(io),
+                        RateLimit::new(
+                            Service::new(config.clone(), request_tx, connection.clone()),
+                            config,
+                            connection,
+                        ),
+                    )
                     .await
                 {
                     error!("Failed to serve connection: {err}");
@@ -87,84 +180,487 @@ pub async fn run(
 
 #[derive(Debug, Clone)]
 struct Service {
-    filters: Vec<Regex>,
-    timeout: Duration,
+    config: Arc<Config>,
     request_tx: Sender<crate::ChannelItem>,
+    connection: Arc<common::Connection>,
 }
 
 impl Service {
-    fn new(filters: Vec<Regex>, timeout: Duration, request_tx: Sender<crate::ChannelItem>) -> Self {
+    fn new(
+        config: Arc<Config>,
+        request_tx: Sender<crate::ChannelItem>,
+        connection: Arc<common::Connection>,
+    ) -> Self {
         Self {
-            filters,
-            timeout,
+            config,
             request_tx,
+            connection,
         }
     }
+
+    /// Applies access lists and filters, returning the tunnel serving the request and its timeouts, or the rejection
+    fn route<B>(&self, req: &Request<B>) -> Result<(String, Timeouts), Problem> {
+        let client_ip = acl::client_ip(
+            self.connection.remote_addr.ip(),
+            req.headers(),
+            &self.config.trusted_proxies,
+        );
+        admit(&self.config, client_ip, req).map_err(|(reason, problem)| {
+            metrics::REJECTIONS.with_label_values(&[reason]).inc();
+            problem
+        })
+    }
+}
+
+/// Access lists and filters, shared with the rate limiter so denied callers don't take tokens
+///
+/// Rejections come with their metric label
+pub(crate) fn admit<B>(
+    config: &Config,
+    client_ip: IpAddr,
+    req: &Request<B>,
+) -> Result<(String, Timeouts), (&'static str, Problem)> {
+    if !config.acl.allows(client_ip) {
+        return Err((
+            "acl",
+            Problem::new(Kind::Rejected).with_detail("Client not allowed"),
+        ));
+    }
+    let Some(route) = filter::route(&config.filters, req, client_ip) else {
+        return Err((
+            "filter",
+            Problem::new(Kind::Rejected)
+                .with_status(config.filters_status)
+                .with_detail("No tunnel matches the request"),
+        ));
+    };
+    let tunnel = route.tunnel;
+    if config
+        .tunnel_acls
+        .get(tunnel)
+        .is_some_and(|acl| !acl.allows(client_ip))
+    {
+        return Err((
+            "tunnel_acl",
+            Problem::new(Kind::Rejected)
+                .with_detail(format!("Client not allowed on tunnel {tunnel}")),
+        ));
+    }
+    let timeouts = route
+        .timeouts
+        .or(config
+            .tunnel_timeouts
+            .get(tunnel)
+            .copied()
+            .unwrap_or_default())
+        .or(config.timeouts);
+    Ok((tunnel.to_owned(), timeouts))
 }
 
 impl service::Service<Request<Incoming>> for Service {
-    type Response = Response<Full<Bytes>>;
-    type Error = Error;
+    type Response = Response<Body>;
+    type Error = Infallible;
     type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>> + Send>>;
 
     fn call(&self, req: Request<Incoming>) -> Self::Future {
-        debug!("Received request {req:?}");
-
         // filter outside the future to avoid filters lifetime and avoid cloning channel if unneeded all at once
-        let request_tx = filtert_req(&self.filters, req.uri().path())
-            .then(|| (self.timeout, self.request_tx.clone()));
+        let route = self.route(&req);
+        let (config, request_tx, connection) = (
+            self.config.clone(),
+            self.request_tx.clone(),
+            self.connection.clone(),
+        );
+        let call = Call::new(
+            &self.config,
+            &self.connection,
+            &req,
+            route
+                .as_ref()
+                .map(|(tunnel, _)| tunnel.as_str())
+                .unwrap_or_default()
+                .to_owned(),
+        );
+        let access_config = self.config.clone();
+        let span = info_span!(
+            "request",
+            method = %call.method,
+            path = call.uri.path(),
+            tunnel = call.tunnel,
+            request_id = call.request_id,
+            status = field::Empty,
+        );
+        span.in_scope(|| debug!("Received request {req:?}"));
+        let request_id = call.request_id.clone();
+        // errors are answered in the format the caller prefers
+        let accept = req.headers().get(ACCEPT).cloned();
+        let problem_accept = accept.clone();
 
-        Box::pin(async move {
-            let Some((call_timeout, request_tx)) = request_tx else {
-                return Ok(Response::builder()
-                    .status(StatusCode::NOT_FOUND)
-                    .body(Full::default())?);
-            };
+        let future = async move {
+            let (tunnel, timeouts) = route?;
+            // the deadline covers the caller upload too
+            let deadline =
+                timeouts::deadline(Instant::now(), timeouts.total.unwrap_or(config.timeout));
 
             let (head, body) = req.into_parts();
 
+            // here we use `iter` instead of `into_iter` to avoid having to deal with `Option<HeaderName>` on repeated names
+            let mut headers = head
+                .headers
+                .iter()
+                .filter_map(|(k, v)| {
+                    // avoid sending HOST header, the original value travels as `X-Forwarded-Host`
+                    (k != HOST).then_some((k.clone(), v.clone()))
+                })
+                .collect::<Vec<_>>();
+            // HTTP/2 requests carry the host in the uri
+            let host = head.headers.get(HOST).cloned().or_else(|| {
+                head.uri
+                    .authority()
+                    .and_then(|authority| HeaderValue::from_str(authority.as_str()).ok())
+            });
+            // the caller may be part of a trace already
+            telemetry::set_parent(&Span::current(), &headers);
+            config.request_headers.filter(&mut headers);
+            headers::forwarded(
+                &mut headers,
+                &connection,
+                host.as_ref(),
+                acl::contains(&config.trusted_proxies, connection.remote_addr.ip()),
+            );
+            config.request_headers.rewrite(&mut headers);
+            telemetry::inject(&Span::current(), &mut headers);
+
+            let (body, trailers) = timeout_at(deadline, body::collect(body, timeouts.idle))
+                .await
+                .map_err(|_| {
+                    Problem::new(Kind::Timeout)
+                        .with_status(StatusCode::REQUEST_TIMEOUT)
+                        .with_detail("Request body not received in time")
+                })?
+                .map_err(|_| {
+                    Problem::new(Kind::Timeout)
+                        .with_status(StatusCode::REQUEST_TIMEOUT)
+                        .with_detail("Request body idle for too long")
+                })??;
+            metrics::REQUEST_BYTES
+                .with_label_values(&[&tunnel])
+                .inc_by(body.len() as u64);
+
             let request = common::IncomingRequest {
                 method: head.method,
                 uri: head.uri,
-                // here we use `iter` instead of `into_iter` to avoid having to deal with `Option<HeaderName>` on repeated names
-                headers: head
-                    .headers
-                    .iter()
-                    .filter_map(|(k, v)| {
-                        // avoid sending HOST header
-                        (k == HOST).then_some((k.clone(), v.clone()))
-                    })
-                    .collect(),
-                body: body.collect().await?.to_bytes(),
+                version: head.version,
+                headers,
+                body,
+                trailers,
+                connection: common::Connection::clone(&connection),
+                request_id: request_id.clone(),
+                // what's left after the upload
+                timeouts: Timeouts {
+                    total: Some(deadline.saturating_duration_since(Instant::now())),
+                    ..timeouts
+                },
             };
 
             let (oneshot_tx, oneshot_rx) = oneshot::channel();
-            if request_tx.send((request, oneshot_tx)).await.is_err() {
-                return Ok(Response::builder()
-                    .status(StatusCode::INTERNAL_SERVER_ERROR)
-                    .body(Full::default())?);
+            // a full queue means the tunnels can't keep up, better to tell the caller than to wait
+            let problem = match request_tx.try_send((request, tunnel.clone(), deadline, oneshot_tx))
+            {
+                Ok(()) => None,
+                Err(TrySendError::Full(_)) => {
+                    Some(Problem::new(Kind::Overloaded).with_detail("Dispatch queue full"))
+                }
+                Err(TrySendError::Closed(_)) => {
+                    Some(Problem::new(Kind::Internal).with_detail("Dispatcher gone"))
+                }
+            };
+            if let Some(problem) = problem {
+                metrics::REJECTIONS.with_label_values(&["overload"]).inc();
+                debug!(
+                    "Request rejected with {problem}, {} queued",
+                    request_tx.max_capacity() - request_tx.capacity()
+                );
+                return Err(problem.into());
+            }
+
+            // dropping `oneshot_rx` on timeout lets the tunnel reap the pending request
+            let Reply { response, served } = timeout_at(deadline, oneshot_rx).await??;
+            let mut response = response.unwrap_or_else(|problem| {
+                debug!("Request failed: {problem}");
+                if problem.kind == Kind::Timeout {
+                    metrics::TIMEOUTS.with_label_values(&[&tunnel]).inc();
+                }
+                config.error_pages.render(
+                    problem,
+                    accept.as_ref(),
+                    &request_id,
+                    !config.hide_error_details,
+                )
+            });
+            config.response_headers.filter(&mut response.headers);
+            config.response_headers.rewrite(&mut response.headers);
+            if !response.trailers.is_empty() {
+                // HTTP/1 only sends trailers with chunked encoding, when announced
+                response.headers.retain(|(k, _)| k != CONTENT_LENGTH);
+                if !response.headers.iter().any(|(k, _)| k == TRAILER) {
+                    let names = response
+                        .trailers
+                        .iter()
+                        .map(|(k, _)| k.as_str())
+                        .collect::<Vec<_>>()
+                        .join(", ");
+                    response
+                        .headers
+                        .push((TRAILER, HeaderValue::from_str(&names)?));
+                }
             }
 
-            let response = timeout(call_timeout, oneshot_rx).await??;
+            metrics::RESPONSE_BYTES
+                .with_label_values(&[&tunnel])
+                .inc_by(response.body.len() as u64);
 
-            let mut builder = Response::builder().status(response.status);
+            // the response version is the one of the caller connection, not the backend one
+            let mut builder = Response::builder()
+                .status(response.status)
+                .extension(served);
             for (k, v) in response.headers {
                 builder = builder.header(k, v);
             }
-            Ok(builder.body(Full::from(response.body))?)
+            Ok(builder.body(Body::new(response.body, response.trailers))?)
+        };
+
+        Box::pin(async move {
+            let res = future.instrument(span.clone()).await;
+            let mut response = res.unwrap_or_else(|err| {
+                let problem = match err {
+                    Error::Problem(problem) => problem,
+                    Error::Timeout(_) => {
+                        metrics::TIMEOUTS.with_label_values(&[&call.tunnel]).inc();
+                        Problem::new(Kind::Timeout).with_detail("No response in time")
+                    }
+                    err => Problem::new(Kind::Internal).with_detail(err.to_string()),
+                };
+                span.in_scope(|| debug!("Request failed: {problem}"));
+                problem_response(
+                    &access_config,
+                    problem,
+                    problem_accept.as_ref(),
+                    &call.request_id,
+                )
+            });
+            span.record("status", response.status().as_u16());
+            if let Ok(value) = HeaderValue::from_str(&call.request_id) {
+                response.headers_mut().insert(X_REQUEST_ID, value);
+            }
+            call.record(&response, access_config.access_log.as_ref());
+            Ok(response)
         })
     }
 }
 
+/// A problem answered by porcod itself, bypassing the response header policy
+pub(crate) fn problem_response<B: From<Bytes>>(
+    config: &Config,
+    problem: Problem,
+    accept: Option<&HeaderValue>,
+    request_id: &str,
+) -> Response<B> {
+    let problem =
+        config
+            .error_pages
+            .render(problem, accept, request_id, !config.hide_error_details);
+    let mut response = Response::new(B::from(problem.body));
+    *response.status_mut() = problem.status;
+    response.headers_mut().extend(problem.headers);
+    response
+}
+
+/// What's needed to account for a request once answered
+#[derive(Debug)]
+pub(crate) struct Call {
+    client_ip: IpAddr,
+    method: Method,
+    uri: Uri,
+    version: Version,
+    referer: Option<HeaderValue>,
+    user_agent: Option<HeaderValue>,
+    tunnel: String,
+    request_id: String,
+    start: Instant,
+}
+
+impl Call {
+    pub(crate) fn new<B>(
+        config: &Config,
+        connection: &common::Connection,
+        req: &Request<B>,
+        tunnel: String,
+    ) -> Self {
+        Self {
+            client_ip: acl::client_ip(
+                connection.remote_addr.ip(),
+                req.headers(),
+                &config.trusted_proxies,
+            ),
+            method: req.method().clone(),
+            uri: req.uri().clone(),
+            version: req.version(),
+            referer: req.headers().get(REFERER).cloned(),
+            user_agent: req.headers().get(USER_AGENT).cloned(),
+            tunnel,
+            request_id: headers::request_id(req.headers().get(X_REQUEST_ID)),
+            start: Instant::now(),
+        }
+    }
+
+    pub(crate) fn request_id(&self) -> &str {
+        &self.request_id
+    }
+
+    pub(crate) fn record<B: hyper::body::Body>(
+        &self,
+        response: &Response<B>,
+        access_log: Option<&AccessLog>,
+    ) {
+        let latency = self.start.elapsed();
+        let tunnel = self.tunnel.as_str();
+        let status = response.status();
+        metrics::REQUESTS
+            .with_label_values(&[
+                common::metrics::method_label(&self.method),
+                status.as_str(),
+                tunnel,
+            ])
+            .inc();
+        metrics::REQUEST_DURATION
+            .with_label_values(&[tunnel])
+            .observe(latency.as_secs_f64());
+
+        let Some(access_log) = access_log else {
+            return;
+        };
+        let served = response.extensions().get::<Served>();
+        access_log.log(&access_log::Entry {
+            client_ip: self.client_ip,
+            method: &self.method,
+            uri: &self.uri,
+            version: self.version,
+            referer: self.referer.as_ref().and_then(|value| value.to_str().ok()),
+            user_agent: self
+                .user_agent
+                .as_ref()
+                .and_then(|value| value.to_str().ok()),
+            status: status.as_str(),
+            bytes: response.body().size_hint().lower(),
+            latency,
+            tunnel,
+            session: served.and_then(|served| served.session.as_deref()),
+            request_id: &self.request_id,
+        });
+    }
+}
+
 #[derive(Debug, thiserror::Error)]
 #[error(transparent)]
 pub enum Error {
     Http(#[from] http::Error),
+    InvalidHeader(#[from] http::header::InvalidHeaderValue),
     Hyper(#[from] hyper::Error),
     Timeout(#[from] Elapsed),
     ChannelClosed(#[from] RecvError),
+    Problem(#[from] Problem),
 }
 
-fn filtert_req(filters: &[Regex], path: &str) -> bool {
-    filters.is_empty() || filters.iter().any(|regex| regex.is_match(path))
+#[cfg(test)]
+mod tests {
+    use http::uri::Scheme;
+    use tokio::{
+        io::{duplex, AsyncReadExt, AsyncWriteExt},
+        sync::mpsc,
+        time::sleep,
+    };
+
+    use super::*;
+
+    fn config(timeouts: Timeouts) -> Config {
+        Config {
+            acl: Acl::default(),
+            trusted_proxies: vec![],
+            proxy_protocol: false,
+            tunnel_acls: HashMap::new(),
+            filters: vec![],
+            filters_status: StatusCode::NOT_FOUND,
+            rate_limiter: Limiter::new(vec![]),
+            timeout: Duration::from_secs(60),
+            timeouts,
+            tunnel_timeouts: HashMap::new(),
+            request_headers: HeaderPolicy::default(),
+            response_headers: HeaderPolicy::default(),
+            access_log: None,
+            health: Arc::default(),
+            hide_error_details: false,
+            error_pages: ErrorPages::default(),
+        }
+    }
+
+    /// Uploads a body one byte every `every`, never completing it, returning the response head and when it came
+    async fn slow_upload(timeouts: Timeouts, every: Duration) -> (String, Duration) {
+        let (request_tx, _request_rx) = mpsc::channel(1);
+        let connection = Arc::new(common::Connection {
+            remote_addr: "127.0.0.1:40000".parse().unwrap(),
+            local_port: 8080,
+            scheme: Scheme::HTTP,
+            tls_version: None,
+            sni: None,
+            alpn: None,
+        });
+        let service = Service::new(Arc::new(config(timeouts)), request_tx, connection);
+        let (client, server) = duplex(1024);
+        tokio::spawn(async move {
+            let _ = auto::Builder::new(TokioExecutor::new())
+                .serve_connection(TokioIo::new(server), service)
+                .await;
+        });
+
+        let start = Instant::now();
+        let (mut reader, mut writer) = tokio::io::split(client);
+        tokio::spawn(async move {
+            writer
+                .write_all(b"POST / HTTP/1.1\r\nHost: example.com\r\nContent-Length: 1000\r\n\r\n")
+                .await?;
+            loop {
+                sleep(every).await;
+                writer.write_all(b"x").await?;
+            }
+            #[allow(unreachable_code)]
+            std::io::Result::Ok(())
+        });
+        let mut head = vec![0; 12];
+        reader.read_exact(&mut head).await.unwrap();
+        (String::from_utf8(head).unwrap(), start.elapsed())
+    }
+
+    #[tokio::test]
+    async fn slow_body_total() {
+        let timeouts = Timeouts {
+            total: Some(Duration::from_millis(300)),
+            ..Timeouts::default()
+        };
+        // the body keeps coming, but not in time
+        let (head, elapsed) = slow_upload(timeouts, Duration::from_millis(20)).await;
+        assert_eq!(head, "HTTP/1.1 408");
+        assert!(elapsed >= Duration::from_millis(300));
+        assert!(elapsed < Duration::from_secs(5));
+    }
+
+    #[tokio::test]
+    async fn idle_body() {
+        let timeouts = Timeouts {
+            idle: Some(Duration::from_millis(100)),
+            ..Timeouts::default()
+        };
+        let (head, elapsed) = slow_upload(timeouts, Duration::from_secs(10)).await;
+        assert_eq!(head, "HTTP/1.1 408");
+        assert!(elapsed < Duration::from_secs(5));
+    }
 }